@@ -0,0 +1,147 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body::Incoming, Request, Response, StatusCode};
+use serde::Serialize;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tracing::{error, info, warn};
+use twilight_gateway::CloseFrame;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    cache::{bad_request_body, not_found_body},
+    server::query_param,
+    sharding,
+    state::{Proxy, State},
+};
+
+/// A client connection as listed by the admin API.
+#[derive(Serialize)]
+struct SessionInfo<'a> {
+    session_id: &'a str,
+    addr: SocketAddr,
+    shard_id: u32,
+}
+
+pub fn handle_reshard(request: &Request<Incoming>, proxy: Arc<Proxy>) -> Response<Full<Bytes>> {
+    let shard_count = match query_param(request, "shards").map(str::parse::<u32>) {
+        Some(Ok(0) | Err(_)) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Full::from("Invalid shard count"))
+                .unwrap();
+        }
+        Some(Ok(shard_count)) => Some(shard_count),
+        None => None,
+    };
+
+    info!("Resharding requested via admin API");
+    metrics::counter!("gateway_admin_actions", "action" => "reshard").increment(1);
+
+    tokio::spawn(async move {
+        let shard_count = match shard_count {
+            Some(shard_count) => shard_count,
+            None => match sharding::recommended_shards(&proxy.client()).await {
+                Ok(shard_count) => shard_count,
+                Err(e) => {
+                    error!("Failed to fetch recommended shard count: {e}");
+                    return;
+                }
+            },
+        };
+
+        sharding::reshard(proxy, shard_count).await;
+    });
+
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Full::from("Resharding started"))
+        .unwrap()
+}
+
+pub fn handle_sessions(state: &State) -> Response<Full<Bytes>> {
+    let connections = state.connections.read().unwrap();
+
+    let sessions: Vec<_> = connections
+        .iter()
+        .map(|(session_id, connection)| SessionInfo {
+            session_id,
+            addr: connection.addr,
+            shard_id: connection.shard_id,
+        })
+        .collect();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Full::from(to_string(&sessions).unwrap()))
+        .unwrap()
+}
+
+pub fn handle_close_session(session_id: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+
+    if !state.close_connection(session_id) {
+        return response
+            .status(404)
+            .body(not_found_body("session"))
+            .unwrap();
+    }
+
+    info!("Closing the connection of session {session_id} via admin API");
+    metrics::counter!("gateway_admin_actions", "action" => "close_session").increment(1);
+
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Full::from("Closing connection"))
+        .unwrap()
+}
+
+/// Restart a shard or make it resume its session.
+///
+/// Both close the shard's connection, `restart` invalidates the session while
+/// `resume` keeps it. Shards whose task is no longer running are started again.
+pub fn handle_shard_action(
+    value: &str,
+    action: &str,
+    proxy: &Proxy,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Ok(shard_id) = value.parse::<u32>() else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(shard) = state.shards.iter().find(|shard| shard.id == shard_id) else {
+        return response.status(404).body(not_found_body("shard")).unwrap();
+    };
+
+    let resume = action == "resume";
+
+    info!("[Shard {shard_id}] Received {action} via admin API");
+    metrics::counter!("gateway_admin_actions", "action" => action.to_owned(), "shard" => shard_id.to_string()).increment(1);
+
+    if shard.commands.read().unwrap().is_closed() {
+        sharding::respawn(proxy, state.shard_count, shard, resume);
+    } else {
+        let close_frame = if resume {
+            CloseFrame::RESUME
+        } else {
+            shard.session.clear();
+            CloseFrame::NORMAL
+        };
+
+        let result = shard.sender.read().unwrap().close(close_frame);
+        if let Err(e) = result {
+            warn!("[Shard {shard_id}] Failed to close the connection: {e}");
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .body(Full::from("Closing shard connection"))
+        .unwrap()
+}
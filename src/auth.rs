@@ -0,0 +1,310 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hyper::{header::AUTHORIZATION, Request};
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
+};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use std::{
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{ClientKey, HttpAccess, CONFIG},
+    state::Shard,
+};
+
+/// Compare two secrets without leaking where they differ or how long they are.
+pub fn secrets_match(a: &str, b: &str) -> bool {
+    digest(&SHA256, a.as_bytes())
+        .as_ref()
+        .ct_eq(digest(&SHA256, b.as_bytes()).as_ref())
+        .into()
+}
+
+/// Verify a token of the form `<name>.<expires_at>.<signature>`, where the
+/// signature is the unpadded URL-safe base64 HMAC-SHA256 of
+/// `<name>.<expires_at>` with the client's key and `expires_at` a UNIX
+/// timestamp in seconds.
+fn verify_signed_token<'a>(token: &str, clients: &'a [ClientKey]) -> Option<&'a ClientKey> {
+    let mut parts = token.split('.');
+    let (Some(name), Some(expires_at), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+
+    let client = clients.iter().find(|client| client.name == name)?;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if expires_at.parse::<u64>().ok()? < now {
+        return None;
+    }
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, client.key.as_bytes());
+    hmac::verify(&key, format!("{name}.{expires_at}").as_bytes(), &signature).ok()?;
+
+    Some(client)
+}
+
+/// Find the client that a key or signed token belongs to.
+pub fn authenticate(token: &str) -> Option<&'static ClientKey> {
+    // Tokens may be prefixed like Discord tokens in IDENTIFY and RESUME
+    let token = token.split_whitespace().last()?;
+
+    verify_signed_token(token, &CONFIG.clients).or_else(|| {
+        CONFIG
+            .clients
+            .iter()
+            .find(|client| secrets_match(&client.key, token))
+    })
+}
+
+/// Log and count a failed authentication or authorization.
+pub fn audit_failure(addr: SocketAddr, context: &'static str, reason: &str) {
+    warn!("[{addr}] Denied {context}: {reason}");
+    metrics::counter!("gateway_auth_failures", "context" => context).increment(1);
+}
+
+/// What a client that identified or resumed is allowed to do.
+#[derive(Clone, Copy)]
+pub enum Access {
+    Denied,
+    /// The client sent the bot token, or token validation is disabled.
+    Bot,
+    Client(&'static ClientKey),
+}
+
+impl Access {
+    /// Check the token sent in an IDENTIFY or RESUME for a shard.
+    pub fn for_shard(addr: SocketAddr, context: &'static str, token: &str, shard: &Shard) -> Self {
+        if let Some(client) = authenticate(token) {
+            if client.allows_bot(&shard.bot.name) && client.allows_shard(shard.id) {
+                return Self::Client(client);
+            }
+
+            audit_failure(
+                addr,
+                context,
+                &format!(
+                    "client {} may not use shard {} of bot {}",
+                    client.name, shard.id, shard.bot.name
+                ),
+            );
+            return Self::Denied;
+        }
+
+        // Once clients are configured, the bot token has to be valid regardless
+        // of validate_token
+        let valid = if CONFIG.clients.is_empty() {
+            shard.validate_token(token)
+        } else {
+            shard.is_bot_token(token)
+        };
+
+        if valid {
+            Self::Bot
+        } else {
+            audit_failure(addr, context, "invalid token");
+            Self::Denied
+        }
+    }
+
+    /// Whether the client may send a payload with this opcode to Discord.
+    pub fn allows_opcode(self, op: u8) -> bool {
+        match self {
+            Self::Denied => false,
+            Self::Bot => true,
+            Self::Client(client) => client.allows_opcode(op),
+        }
+    }
+}
+
+/// Access that the credentials of an HTTP request grant.
+fn http_access<B>(request: &Request<B>) -> HttpAccess {
+    let Some(token) = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return HttpAccess::None;
+    };
+
+    if CONFIG
+        .admin_token
+        .as_deref()
+        .is_some_and(|admin_token| secrets_match(admin_token, token))
+    {
+        return HttpAccess::Admin;
+    }
+
+    authenticate(token).map_or(HttpAccess::None, |client| client.http)
+}
+
+/// Check whether an HTTP request may access an endpoint that requires
+/// `required` access.
+///
+/// Read access is open to everyone unless clients are configured.
+pub fn allows_http<B>(request: &Request<B>, addr: SocketAddr, required: HttpAccess) -> bool {
+    if required == HttpAccess::Read && CONFIG.clients.is_empty() {
+        return true;
+    }
+
+    let allowed = http_access(request) >= required;
+
+    if !allowed {
+        audit_failure(
+            addr,
+            "HTTP request",
+            &format!(
+                "missing or insufficient credentials for {}",
+                request.uri().path()
+            ),
+        );
+    }
+
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ring::hmac;
+
+    use std::{
+        collections::HashSet,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{secrets_match, verify_signed_token};
+    use crate::config::{ClientKey, HttpAccess};
+
+    fn client(name: &str, key: &str) -> ClientKey {
+        ClientKey {
+            name: name.to_string(),
+            key: key.to_string(),
+            shards: None,
+            opcodes: None,
+            bots: None,
+            http: HttpAccess::Read,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(name: &str, expires_at: u64, key: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, key.as_bytes());
+        let signature = hmac::sign(&key, format!("{name}.{expires_at}").as_bytes());
+
+        format!(
+            "{name}.{expires_at}.{}",
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    #[test]
+    fn accepts_valid_signed_tokens() {
+        let clients = [
+            client("worker", "a-sufficiently-long-key-for-worker"),
+            client("dashboard", "a-sufficiently-long-key-for-dashboard"),
+        ];
+        let token = sign(
+            "dashboard",
+            now() + 60,
+            "a-sufficiently-long-key-for-dashboard",
+        );
+
+        let found = verify_signed_token(&token, &clients).unwrap();
+        assert_eq!(found.name, "dashboard");
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let clients = [client("worker", "a-sufficiently-long-key-for-worker")];
+        let token = sign("worker", now() - 1, "a-sufficiently-long-key-for-worker");
+
+        assert!(verify_signed_token(&token, &clients).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let clients = [client("worker", "a-sufficiently-long-key-for-worker")];
+        let expires_at = now() + 60;
+
+        // Signed with another key
+        let token = sign("worker", expires_at, "some-other-key-of-the-same-length");
+        assert!(verify_signed_token(&token, &clients).is_none());
+
+        // Signature of another expiry
+        let signed = sign("worker", expires_at, "a-sufficiently-long-key-for-worker");
+        let signature = signed.rsplit('.').next().unwrap();
+        let token = format!("worker.{}.{signature}", expires_at + 3600);
+        assert!(verify_signed_token(&token, &clients).is_none());
+
+        // Signature that is not base64
+        let token = format!("worker.{expires_at}.not base64!");
+        assert!(verify_signed_token(&token, &clients).is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_clients() {
+        let clients = [client("worker", "a-sufficiently-long-key-for-worker")];
+        let token = sign("intruder", now() + 60, "a-sufficiently-long-key-for-worker");
+
+        assert!(verify_signed_token(&token, &clients).is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let clients = [client("worker", "a-sufficiently-long-key-for-worker")];
+        let token = sign("worker", now() + 60, "a-sufficiently-long-key-for-worker");
+
+        for token in [
+            String::new(),
+            "a-sufficiently-long-key-for-worker".to_string(),
+            format!("{token}.extra"),
+            token.replacen('.', ".soon", 1),
+        ] {
+            assert!(verify_signed_token(&token, &clients).is_none(), "{token}");
+        }
+    }
+
+    #[test]
+    fn restricts_clients_to_their_shards_bots_and_opcodes() {
+        let unrestricted = client("worker", "a-sufficiently-long-key-for-worker");
+        assert!(unrestricted.allows_shard(1023));
+        assert!(unrestricted.allows_bot("any"));
+        assert!(unrestricted.allows_opcode(8));
+
+        let restricted = ClientKey {
+            shards: Some(HashSet::from([0, 1])),
+            opcodes: Some(HashSet::from([1])),
+            bots: Some(HashSet::from(["main".to_string()])),
+            ..unrestricted
+        };
+        assert!(restricted.allows_shard(0));
+        assert!(restricted.allows_shard(1));
+        assert!(!restricted.allows_shard(2));
+        assert!(restricted.allows_bot("main"));
+        assert!(!restricted.allows_bot("other"));
+        assert!(restricted.allows_opcode(1));
+        assert!(!restricted.allows_opcode(8));
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(secrets_match("secret", "secret"));
+        assert!(!secrets_match("secret", "Secret"));
+        assert!(!secrets_match("secret", "secret2"));
+    }
+}
@@ -1,32 +1,47 @@
 use bytes::Bytes;
+use dashmap::DashMap;
 #[cfg(feature = "simd-json")]
 use halfbrown::hashmap;
-use http_body_util::Full;
-use hyper::Response;
-use serde::Serialize;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::Incoming, Request, Response};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
 #[cfg(not(feature = "simd-json"))]
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
 use simd_json::{to_string, OwnedValue};
-use twilight_cache_inmemory::{DefaultCacheModels, InMemoryCache, InMemoryCacheStats, UpdateCache};
+use twilight_cache_inmemory::{
+    model::{CachedGuild, CachedMessage},
+    permission::{ChannelErrorType, RootErrorType},
+    InMemoryCache, InMemoryCacheStats,
+};
+use twilight_gateway::Event;
 use twilight_model::{
     channel::{message::Sticker, Channel, StageInstance},
     gateway::{
-        payload::incoming::GuildDelete,
+        payload::incoming::{GuildCreate, GuildDelete},
         presence::{Presence, UserOrId},
         OpCode,
     },
-    guild::{scheduled_event::GuildScheduledEvent, Emoji, Guild, Member, Role},
+    guild::{scheduled_event::GuildScheduledEvent, Emoji, Guild, Member, Permissions, Role},
     id::{
-        marker::{ChannelMarker, GuildMarker, UserMarker},
+        marker::{ChannelMarker, GuildMarker, RoleMarker, UserMarker},
         Id,
     },
+    user::User,
     voice::VoiceState,
 };
 
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{config::CONFIG, model::JsonObject, state::State};
+use crate::{
+    config::{self, CONFIG},
+    messages::{MessageHistory, PastMessage},
+    model::JsonObject,
+    search::MemberNames,
+    server::query_param,
+    state::State,
+};
 
 #[derive(Serialize)]
 pub struct Payload<T> {
@@ -36,19 +51,275 @@ pub struct Payload<T> {
     pub s: usize,
 }
 
-pub struct Guilds(Arc<InMemoryCache>);
+/// Index of the guild that each cached guild channel belongs to, shared by
+/// the caches of all shards.
+pub type ChannelGuilds = DashMap<Id<ChannelMarker>, Id<GuildMarker>>;
+
+/// Number of members per guild that joined since its GUILD_CREATE, but were
+/// not cached because of the members scope. The cache does not count them in
+/// the member count of the guild.
+type UncachedJoins = DashMap<Id<GuildMarker>, u64>;
+
+pub struct Guilds(
+    Arc<InMemoryCache>,
+    Arc<ChannelGuilds>,
+    MemberNames,
+    MessageHistory,
+    Arc<config::Cache>,
+    UncachedJoins,
+);
 
 impl Guilds {
-    pub const fn new(cache: Arc<InMemoryCache>) -> Self {
-        Self(cache)
+    pub fn new(
+        cache: Arc<InMemoryCache>,
+        channel_guilds: Arc<ChannelGuilds>,
+        config: Arc<config::Cache>,
+    ) -> Self {
+        Self(
+            cache,
+            channel_guilds,
+            MemberNames::default(),
+            MessageHistory::default(),
+            config,
+            UncachedJoins::default(),
+        )
     }
 
     pub fn cache(&self) -> Arc<InMemoryCache> {
         self.0.clone()
     }
 
-    pub fn update(&self, value: impl UpdateCache<DefaultCacheModels>) {
-        self.0.update(value);
+    pub fn update(&self, mut event: Event) {
+        if !self.in_scope(&mut event) {
+            return;
+        }
+
+        self.index_channels(&event);
+
+        if let Event::GuildDelete(guild_delete) = &event {
+            self.5.remove(&guild_delete.id);
+        }
+
+        if self.4.members {
+            self.2.update(&event);
+        }
+
+        if self.4.messages > 0 {
+            self.3.update(&self.0, &event, self.4.messages);
+        }
+
+        self.0.update(event);
+    }
+
+    /// Apply the per-guild scopes and limits of the cache config to an event.
+    /// Returns `false` if the event should not be cached at all.
+    fn in_scope(&self, event: &mut Event) -> bool {
+        match event {
+            Event::GuildCreate(guild_create) => {
+                if let GuildCreate::Available(guild) = &mut **guild_create {
+                    // The member count is up to date again
+                    self.5.remove(&guild.id);
+
+                    let current_user_id = self.0.current_user().map(|user| user.id);
+                    let members_scope = &self.4.members_scope;
+                    let presences_scope = &self.4.presences_scope;
+
+                    let mut members = 0;
+                    let cache_members =
+                        members_scope.allows(guild.id) && !self.4.is_large(guild.member_count);
+
+                    guild.members.retain(|member| {
+                        if Some(member.user.id) == current_user_id {
+                            return true;
+                        }
+
+                        let keep = cache_members && members_scope.has_room(members);
+                        members += usize::from(keep);
+                        keep
+                    });
+
+                    if presences_scope.allows(guild.id) {
+                        if let Some(max) = presences_scope.max_per_guild {
+                            guild.presences.truncate(max);
+                        }
+                    } else {
+                        guild.presences.clear();
+                    }
+                }
+
+                true
+            }
+            Event::MemberAdd(member_add) => {
+                if self.caches_member(member_add.guild_id, member_add.member.user.id) {
+                    return true;
+                }
+
+                // The cache increments the member count when it handles the
+                // event, which it doesn't do for members that are not cached
+                *self.5.entry(member_add.guild_id).or_default() += 1;
+
+                false
+            }
+            Event::MemberChunk(chunk) => {
+                let guild_id = chunk.guild_id;
+                let members_scope = &self.4.members_scope;
+                let presences_scope = &self.4.presences_scope;
+
+                if !members_scope.allows(guild_id) && !presences_scope.allows(guild_id) {
+                    return false;
+                }
+
+                let current_user_id = self.0.current_user().map(|user| user.id);
+                let member_count = self.member_count(guild_id);
+                let cache_members =
+                    members_scope.allows(guild_id) && !self.4.is_large(member_count);
+
+                let mut members = self
+                    .0
+                    .guild_members(guild_id)
+                    .map_or(0, |members| members.len());
+
+                // Members that are already cached are kept up to date
+                chunk.members.retain(|member| {
+                    if Some(member.user.id) == current_user_id
+                        || self.0.member(guild_id, member.user.id).is_some()
+                    {
+                        return true;
+                    }
+
+                    let keep = cache_members && members_scope.has_room(members);
+                    members += usize::from(keep);
+                    keep
+                });
+
+                let mut presences = self
+                    .0
+                    .guild_presences(guild_id)
+                    .map_or(0, |presences| presences.len());
+
+                chunk.presences.retain(|presence| {
+                    if self.0.presence(guild_id, presence.user.id()).is_some() {
+                        return true;
+                    }
+
+                    let keep =
+                        presences_scope.allows(guild_id) && presences_scope.has_room(presences);
+                    presences += usize::from(keep);
+                    keep
+                });
+
+                true
+            }
+            Event::PresenceUpdate(presence) => {
+                let scope = &self.4.presences_scope;
+                let guild_id = presence.guild_id;
+
+                if self.0.presence(guild_id, presence.user.id()).is_some() {
+                    return true;
+                }
+
+                let cached = self
+                    .0
+                    .guild_presences(guild_id)
+                    .map_or(0, |presences| presences.len());
+
+                scope.allows(guild_id) && scope.has_room(cached)
+            }
+            _ => true,
+        }
+    }
+
+    /// Member count of a guild, including members that joined but were not
+    /// cached.
+    fn member_count(&self, guild_id: Id<GuildMarker>) -> Option<u64> {
+        let member_count = self.0.guild(guild_id)?.member_count()?;
+
+        Some(member_count + self.uncached_joins(guild_id))
+    }
+
+    /// Whether all members of a guild are cached, so that a user missing from
+    /// the cache is known not to be a member.
+    ///
+    /// This is not the case if members are not cached for the guild, or if
+    /// they were limited or never requested.
+    pub fn has_all_members(&self, guild_id: Id<GuildMarker>) -> bool {
+        let cached = self
+            .0
+            .guild_members(guild_id)
+            .map_or(0, |members| members.len());
+
+        self.member_count(guild_id)
+            .is_some_and(|member_count| cached as u64 >= member_count)
+    }
+
+    fn uncached_joins(&self, guild_id: Id<GuildMarker>) -> u64 {
+        self.5.get(&guild_id).map_or(0, |joins| *joins)
+    }
+
+    /// Whether a member that is not cached yet may be added to the cache.
+    fn caches_member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> bool {
+        let scope = &self.4.members_scope;
+
+        // The current member is needed for permission calculation
+        if self
+            .0
+            .current_user()
+            .is_some_and(|current_user| current_user.id == user_id)
+        {
+            return true;
+        }
+
+        let member_count = self.member_count(guild_id);
+        let cached = self
+            .0
+            .guild_members(guild_id)
+            .map_or(0, |members| members.len());
+
+        scope.allows(guild_id) && !self.4.is_large(member_count) && scope.has_room(cached)
+    }
+
+    /// Keep the channel index in sync with the channels of the cache.
+    fn index_channels(&self, event: &Event) {
+        match event {
+            Event::ChannelCreate(channel) => {
+                if let Some(guild_id) = channel.guild_id {
+                    self.1.insert(channel.id, guild_id);
+                }
+            }
+            Event::ChannelDelete(channel) => {
+                self.1.remove(&channel.id);
+            }
+            Event::GuildCreate(guild_create) => {
+                if let GuildCreate::Available(guild) = &**guild_create {
+                    for channel in guild.channels.iter().chain(&guild.threads) {
+                        self.1.insert(channel.id, guild.id);
+                    }
+                }
+            }
+            Event::GuildDelete(guild_delete) => {
+                // The cache drops the channels of a guild when it is deleted,
+                // even if it only became unavailable
+                if let Some(channel_ids) = self.0.guild_channels(guild_delete.id) {
+                    for channel_id in channel_ids.iter() {
+                        self.1.remove(channel_id);
+                    }
+                }
+            }
+            Event::ThreadCreate(thread) => {
+                if let Some(guild_id) = thread.guild_id {
+                    self.1.insert(thread.id, guild_id);
+                }
+            }
+            Event::ThreadDelete(thread) => {
+                self.1.remove(&thread.id);
+            }
+            Event::ThreadListSync(sync) => {
+                for thread in &sync.threads {
+                    self.1.insert(thread.id, sync.guild_id);
+                }
+            }
+            _ => {}
+        }
     }
 
     pub fn stats(&self) -> InMemoryCacheStats {
@@ -105,7 +376,7 @@ impl Guilds {
         }
     }
 
-    fn channels_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
+    pub fn channels_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
         self.0
             .guild_channels(guild_id)
             .map(|reference| {
@@ -125,7 +396,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn presences_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Presence> {
+    pub fn presences_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Presence> {
         self.0
             .guild_presences(guild_id)
             .map(|reference| {
@@ -149,7 +420,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
+    pub fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
         self.0
             .guild_emojis(guild_id)
             .map(|reference| {
@@ -176,7 +447,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Member> {
+    pub fn member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Member> {
         let member = self.0.member(guild_id, user_id)?;
 
         Some(Member {
@@ -194,7 +465,7 @@ impl Guilds {
         })
     }
 
-    fn members_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Member> {
+    pub fn members_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Member> {
         self.0
             .guild_members(guild_id)
             .map(|reference| {
@@ -206,7 +477,73 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn roles_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Role> {
+    /// Members of a guild ordered by user ID, starting after `after`.
+    pub fn members_page(
+        &self,
+        guild_id: Id<GuildMarker>,
+        after: Option<Id<UserMarker>>,
+        limit: usize,
+    ) -> Vec<Member> {
+        let Some(reference) = self.0.guild_members(guild_id) else {
+            return Vec::new();
+        };
+
+        let mut user_ids: Vec<_> = reference
+            .iter()
+            .copied()
+            .filter(|user_id| after.map_or(true, |after| *user_id > after))
+            .collect();
+        drop(reference);
+
+        user_ids.sort_unstable();
+
+        user_ids
+            .into_iter()
+            .filter_map(|user_id| self.member(guild_id, user_id))
+            .take(limit)
+            .collect()
+    }
+
+    /// Members of a guild whose username, global name or nickname starts with
+    /// or contains the query.
+    pub fn search_members(
+        &self,
+        guild_id: Id<GuildMarker>,
+        query: &str,
+        limit: usize,
+    ) -> Vec<Member> {
+        self.2
+            .search(guild_id, query, limit)
+            .into_iter()
+            .filter_map(|user_id| self.member(guild_id, user_id))
+            .collect()
+    }
+
+    /// Most recent messages of a channel, newest first.
+    pub fn messages_in_channel(
+        &self,
+        channel_id: Id<ChannelMarker>,
+        limit: usize,
+    ) -> Vec<CachedMessage> {
+        self.0
+            .channel_messages(channel_id)
+            .map(|reference| {
+                reference
+                    .iter()
+                    .take(limit)
+                    .filter_map(|message_id| Some(self.0.message(*message_id)?.value().clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Previous versions of edited messages and deleted messages of a
+    /// channel, newest first.
+    pub fn message_history(&self, channel_id: Id<ChannelMarker>, limit: usize) -> Vec<PastMessage> {
+        self.3.channel(channel_id, limit)
+    }
+
+    pub fn roles_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Role> {
         self.0
             .guild_roles(guild_id)
             .map(|reference| {
@@ -218,7 +555,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn scheduled_events_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<GuildScheduledEvent> {
+    pub fn scheduled_events_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<GuildScheduledEvent> {
         self.0
             .guild_scheduled_events(guild_id)
             .map(|reference| {
@@ -230,7 +567,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn stage_instances_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<StageInstance> {
+    pub fn stage_instances_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<StageInstance> {
         self.0
             .guild_stage_instances(guild_id)
             .map(|reference| {
@@ -244,7 +581,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn stickers_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Sticker> {
+    pub fn stickers_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Sticker> {
         self.0
             .guild_stickers(guild_id)
             .map(|reference| {
@@ -274,7 +611,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn voice_states_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<VoiceState> {
+    pub fn voice_states_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<VoiceState> {
         self.0
             .guild_voice_states(guild_id)
             .map(|reference| {
@@ -304,7 +641,7 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn threads_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
+    pub fn threads_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
         self.0
             .guild_channels(guild_id)
             .map(|reference| {
@@ -376,7 +713,9 @@ impl Guilds {
                     max_members: guild.max_members(),
                     max_presences: guild.max_presences(),
                     max_video_channel_users: guild.max_video_channel_users(),
-                    member_count: guild.member_count(),
+                    member_count: guild
+                        .member_count()
+                        .map(|member_count| member_count + self.uncached_joins(guild.id())),
                     members,
                     mfa_level: guild.mfa_level(),
                     name: guild.name().to_string(),
@@ -429,6 +768,15 @@ pub fn not_found_body(type_name: &str) -> Full<Bytes> {
     Full::from(body)
 }
 
+fn unavailable_body(type_name: &str) -> Full<Bytes> {
+    let body = to_string(&HashMap::from([(
+        "message",
+        format!("Required {type_name} data is not cached"),
+    )]))
+    .unwrap();
+    Full::from(body)
+}
+
 fn serialize_fail_body(type_name: &str) -> Full<Bytes> {
     let body = to_string(&HashMap::from([(
         "message",
@@ -438,33 +786,146 @@ fn serialize_fail_body(type_name: &str) -> Full<Bytes> {
     Full::from(body)
 }
 
-fn bad_request_body() -> Full<Bytes> {
+pub fn bad_request_body() -> Full<Bytes> {
     let body = to_string(&HashMap::from([("message", "Bad Request")])).unwrap();
     Full::from(body)
 }
 
-pub fn handle_cache_guild(value: &str, state: &State) -> Response<Full<Bytes>> {
-    let response = Response::builder().header("Content-Type", "application/json");
-    let Ok(id) = value.parse::<u64>() else {
-        return response.status(400).body(bad_request_body()).unwrap();
-    };
-    if id == 0 {
-        return response.status(400).body(bad_request_body()).unwrap();
+/// Default number of members returned by a search.
+const DEFAULT_SEARCH_LIMIT: usize = 25;
+/// Default number of messages returned per channel.
+const DEFAULT_MESSAGES_LIMIT: usize = 50;
+/// Maximum number of messages returned per channel.
+const MAX_MESSAGES_LIMIT: usize = 1000;
+/// Default number of members returned per page.
+const DEFAULT_MEMBERS_LIMIT: usize = 100;
+/// Maximum number of members returned per page.
+const MAX_MEMBERS_LIMIT: usize = 1000;
+
+fn parse_id<T>(value: &str) -> Option<Id<T>> {
+    value.parse::<u64>().ok().and_then(Id::new_checked)
+}
+
+/// Find the cache of the shard that a guild is on, if the guild is cached.
+fn guild_cache(guild_id: Id<GuildMarker>, state: &State) -> Option<&Guilds> {
+    state
+        .shard_for_guild(guild_id)
+        .map(|shard| &shard.guilds)
+        .filter(|guilds| guilds.cache().guild(guild_id).is_some())
+}
+
+/// Find the cache of the shard that a channel's guild is on.
+///
+/// Channels outside of guilds, like DMs, are not indexed and can be cached
+/// by any shard, so they are looked up in all of them.
+fn channel_cache(channel_id: Id<ChannelMarker>, state: &State) -> Option<&Guilds> {
+    if let Some(guild_id) = state
+        .channel_guilds
+        .get(&channel_id)
+        .map(|guild_id| *guild_id)
+    {
+        return state.shard_for_guild(guild_id).map(|shard| &shard.guilds);
     }
 
-    let guild_id = Id::<GuildMarker>::new(id);
-    let mut guild = None;
-    for shard in &state.shards {
-        if shard.guilds.cache().guild(guild_id).is_some() {
-            guild = Some(shard.guilds.cache().guild(guild_id).unwrap().clone());
+    state
+        .shards
+        .iter()
+        .map(|shard| &shard.guilds)
+        .find(|guilds| {
+            let cache = guilds.cache();
+            cache.channel(channel_id).is_some() || cache.channel_messages(channel_id).is_some()
+        })
+}
+
+/// Route a request to the cache API and record how long it took.
+pub fn handle_cache_request(
+    segments: &[&str],
+    request: &Request<Incoming>,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let start = Instant::now();
+
+    let (endpoint, response) = match *segments {
+        ["guild", id] => ("guild", handle_cache_guild(id, state)),
+        ["guild", id, "members"] => (
+            "guild_members",
+            handle_cache_guild_members(
+                id,
+                query_param(request, "limit"),
+                query_param(request, "after"),
+                state,
+            ),
+        ),
+        ["guild", id, "members", "search"] => (
+            "guild_members_search",
+            handle_cache_guild_members_search(
+                id,
+                query_param(request, "query"),
+                query_param(request, "limit"),
+                state,
+            ),
+        ),
+        ["guild", id, "member", user] => {
+            ("guild_member", handle_cache_guild_member(id, user, state))
         }
-    }
+        ["guild", id, "permissions", user] => (
+            "permissions",
+            handle_cache_permissions(id, user, query_param(request, "channel"), state),
+        ),
+        ["guild", id, resource] => (
+            "guild_resource",
+            handle_cache_guild_resource(id, resource, state),
+        ),
+        ["channel", id] => ("channel", handle_cache_channel(id, state)),
+        ["channel", id, "messages"] => (
+            "channel_messages",
+            handle_cache_channel_messages(id, query_param(request, "limit"), false, state),
+        ),
+        ["channel", id, "messages", "history"] => (
+            "channel_message_history",
+            handle_cache_channel_messages(id, query_param(request, "limit"), true, state),
+        ),
+        ["channel", id, "messages", message] => (
+            "channel_message",
+            handle_cache_channel_message(id, message, state),
+        ),
+        ["user", id] => ("user", handle_cache_user(id, state)),
+        ["user", id, "guilds"] => ("user_guilds", handle_cache_user_guilds(id, state)),
+        ["user", id, "guilds", guild] => ("user_guild", handle_cache_user_guild(id, guild, state)),
+        ["is_botuser", id] => ("is_botuser", handle_cache_isbotuser(id, state)),
+        _ => {
+            return Response::builder()
+                .status(404)
+                .header("Content-Type", "application/json")
+                .body(not_found_body("endpoint"))
+                .unwrap()
+        }
+    };
+
+    metrics::histogram!("gateway_cache_request_duration_seconds", "endpoint" => endpoint)
+        .record(start.elapsed());
+
+    response
+}
+
+fn handle_cache_guild(value: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(guild_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
 
-    if guild.is_none() {
+    // Clone the guild so that the cache is not locked while serializing
+    let Some(guild) = state.shard_for_guild(guild_id).and_then(|shard| {
+        shard
+            .guilds
+            .cache()
+            .guild(guild_id)
+            .map(|guild| guild.clone())
+    }) else {
         return response.status(404).body(not_found_body("guild")).unwrap();
-    }
+    };
 
-    if let Ok(serialized) = to_string(&guild.unwrap()) {
+    if let Ok(serialized) = to_string(&guild) {
         return response.body(Full::from(serialized)).unwrap();
     }
 
@@ -474,72 +935,226 @@ pub fn handle_cache_guild(value: &str, state: &State) -> Response<Full<Bytes>> {
         .unwrap()
 }
 
-pub fn handle_cache_channel(value: &str, state: &State) -> Response<Full<Bytes>> {
+fn handle_cache_channel(value: &str, state: &State) -> Response<Full<Bytes>> {
     let response = Response::builder().header("Content-Type", "application/json");
-    let Ok(id) = value.parse::<u64>() else {
+    let Some(channel_id) = parse_id(value) else {
         return response.status(400).body(bad_request_body()).unwrap();
     };
-    if id == 0 {
-        return response.status(400).body(bad_request_body()).unwrap();
-    }
 
-    let channel_id = Id::<ChannelMarker>::new(id);
-    let mut channel = None;
-    for shard in &state.shards {
-        if shard.guilds.cache().channel(channel_id).is_some() {
-            channel = Some(shard.guilds.cache().channel(channel_id).unwrap().clone());
-        }
+    let Some(channel) = channel_cache(channel_id, state).and_then(|guilds| {
+        guilds
+            .cache()
+            .channel(channel_id)
+            .map(|channel| channel.clone())
+    }) else {
+        return response
+            .status(404)
+            .body(not_found_body("channel"))
+            .unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&channel) {
+        return response.body(Full::from(serialized)).unwrap();
     }
 
-    if channel.is_none() {
+    response
+        .status(503)
+        .body(serialize_fail_body("channel"))
+        .unwrap()
+}
+
+/// Recent messages of a channel, or with `history` the previous versions of
+/// its edited messages and its deleted messages.
+fn handle_cache_channel_messages(
+    value: &str,
+    limit: Option<&str>,
+    history: bool,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(channel_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let limit = match limit.map(str::parse::<usize>) {
+        None => DEFAULT_MESSAGES_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_MESSAGES_LIMIT).contains(&limit) => limit,
+        Some(_) => return response.status(400).body(bad_request_body()).unwrap(),
+    };
+
+    let Some(guilds) = channel_cache(channel_id, state) else {
         return response
             .status(404)
             .body(not_found_body("channel"))
             .unwrap();
-    }
+    };
 
-    if let Ok(serialized) = to_string(&channel.unwrap()) {
+    let serialized = if history {
+        to_string(&guilds.message_history(channel_id, limit))
+    } else {
+        to_string(&guilds.messages_in_channel(channel_id, limit))
+    };
+
+    if let Ok(serialized) = serialized {
         return response.body(Full::from(serialized)).unwrap();
     }
 
     response
         .status(503)
-        .body(serialize_fail_body("channel"))
+        .body(serialize_fail_body("messages"))
         .unwrap()
 }
 
-pub fn handle_cache_user(value: &str, state: &State) -> Response<Full<Bytes>> {
+fn handle_cache_channel_message(
+    value: &str,
+    message: &str,
+    state: &State,
+) -> Response<Full<Bytes>> {
     let response = Response::builder().header("Content-Type", "application/json");
-    let Ok(id) = value.parse::<u64>() else {
+    let (Some(channel_id), Some(message_id)) = (parse_id(value), parse_id(message)) else {
         return response.status(400).body(bad_request_body()).unwrap();
     };
-    if id == 0 {
+
+    let Some(message) = channel_cache(channel_id, state).and_then(|guilds| {
+        guilds
+            .cache()
+            .message(message_id)
+            .map(|message| message.clone())
+    }) else {
+        return response
+            .status(404)
+            .body(not_found_body("message"))
+            .unwrap();
+    };
+
+    if message.channel_id() != channel_id {
+        return response
+            .status(404)
+            .body(not_found_body("message"))
+            .unwrap();
+    }
+
+    if let Ok(serialized) = to_string(&message) {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body("message"))
+        .unwrap()
+}
+
+fn handle_cache_user(value: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(user_id) = parse_id(value) else {
         return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    // Users are not scoped to a guild, so every shard has to be checked
+    let Some(user) = state
+        .shards
+        .iter()
+        .find_map(|shard| shard.guilds.cache().user(user_id).map(|user| user.clone()))
+    else {
+        return response.status(404).body(not_found_body("user")).unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&user) {
+        return response.body(Full::from(serialized)).unwrap();
     }
 
-    let user_id = Id::<UserMarker>::new(id);
-    let mut user = None;
+    response
+        .status(503)
+        .body(serialize_fail_body("user"))
+        .unwrap()
+}
+
+/// IDs of the guilds that a user shares with the bot, across the caches of
+/// all shards. `None` if not all members of every cached guild are cached,
+/// since the user could be in any of them.
+fn mutual_guilds(user_id: Id<UserMarker>, state: &State) -> Option<Vec<Id<GuildMarker>>> {
+    let mut guild_ids = Vec::new();
+
     for shard in &state.shards {
-        if shard.guilds.cache().user(user_id).is_some() {
-            user = Some(shard.guilds.cache().user(user_id).unwrap().clone());
+        let cache = shard.guilds.cache();
+
+        // Collected first to not hold locks of the cache while checking them
+        let cached_guilds: Vec<_> = cache.iter().guilds().map(|guild| guild.id()).collect();
+
+        if !cached_guilds
+            .into_iter()
+            .all(|guild_id| shard.guilds.has_all_members(guild_id))
+        {
+            return None;
         }
-    }
 
-    if user.is_none() {
-        return response.status(404).body(not_found_body("user")).unwrap();
+        if let Some(user_guilds) = cache.user_guilds(user_id) {
+            user_guilds
+                .iter()
+                .for_each(|guild| guild_ids.push(guild.get()));
+        }
     }
 
-    if let Ok(serialized) = to_string(&user.unwrap()) {
+    guild_ids.sort_unstable();
+
+    Some(guild_ids)
+}
+
+fn handle_cache_user_guilds(value: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(user_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(guild_ids) = mutual_guilds(user_id, state) else {
+        return response
+            .status(409)
+            .body(unavailable_body("member"))
+            .unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&guild_ids) {
         return response.body(Full::from(serialized)).unwrap();
     }
 
     response
         .status(503)
-        .body(serialize_fail_body("user"))
+        .body(serialize_fail_body("guilds"))
         .unwrap()
 }
 
-pub fn handle_cache_isbotuser(value: &str, state: &State) -> Response<Full<Bytes>> {
+/// Check whether a user is a member of a cached guild.
+fn handle_cache_user_guild(value: &str, guild: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let (Some(user_id), Some(guild_id)) = (parse_id::<UserMarker>(value), parse_id(guild)) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    let member = guilds.cache().member(guild_id, user_id).is_some();
+
+    // Without all members, a user that is not cached may still be a member
+    if !member && !guilds.has_all_members(guild_id) {
+        return response
+            .status(409)
+            .body(unavailable_body("member"))
+            .unwrap();
+    }
+
+    response
+        .body(Full::from(
+            to_string(&HashMap::from([("member", member)])).unwrap(),
+        ))
+        .unwrap()
+}
+
+/// Whether a user shares any guild other than `support_guild_id` with the bot.
+///
+/// Superseded by `/cache/user/:id/guilds`, kept for existing clients.
+fn handle_cache_isbotuser(value: &str, state: &State) -> Response<Full<Bytes>> {
     let response = Response::builder().header("Content-Type", "application/json");
     let Ok(id) = value.parse::<u64>() else {
         return response.status(400).body(bad_request_body()).unwrap();
@@ -573,3 +1188,402 @@ pub fn handle_cache_isbotuser(value: &str, state: &State) -> Response<Full<Bytes
         ))
         .unwrap()
 }
+
+fn handle_cache_guild_resource(
+    value: &str,
+    resource: &str,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(guild_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    let serialized = match resource {
+        "channels" => to_string(&guilds.channels_in_guild(guild_id)),
+        "threads" => to_string(&guilds.threads_in_guild(guild_id)),
+        "roles" => to_string(&guilds.roles_in_guild(guild_id)),
+        "emojis" => to_string(&guilds.emojis_in_guild(guild_id)),
+        "stickers" => to_string(&guilds.stickers_in_guild(guild_id)),
+        "voice_states" => to_string(&guilds.voice_states_in_guild(guild_id)),
+        "scheduled_events" => to_string(&guilds.scheduled_events_in_guild(guild_id)),
+        "stage_instances" => to_string(&guilds.stage_instances_in_guild(guild_id)),
+        "presences" => to_string(&guilds.presences_in_guild(guild_id)),
+        _ => return response.status(404).body(not_found_body(resource)).unwrap(),
+    };
+
+    if let Ok(serialized) = serialized {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body(resource))
+        .unwrap()
+}
+
+fn handle_cache_guild_members(
+    value: &str,
+    limit: Option<&str>,
+    after: Option<&str>,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(guild_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let limit = match limit.map(str::parse::<usize>) {
+        None => DEFAULT_MEMBERS_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_MEMBERS_LIMIT).contains(&limit) => limit,
+        Some(_) => return response.status(400).body(bad_request_body()).unwrap(),
+    };
+
+    let after = match after.map(parse_id) {
+        None => None,
+        Some(Some(after)) => Some(after),
+        Some(None) => return response.status(400).body(bad_request_body()).unwrap(),
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&guilds.members_page(guild_id, after, limit)) {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body("members"))
+        .unwrap()
+}
+
+fn handle_cache_guild_members_search(
+    value: &str,
+    query: Option<&str>,
+    limit: Option<&str>,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Some(guild_id) = parse_id(value) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(Ok(query)) = query.map(|query| percent_decode_str(query).decode_utf8()) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    if query.is_empty() {
+        return response.status(400).body(bad_request_body()).unwrap();
+    }
+
+    let limit = match limit.map(str::parse::<usize>) {
+        None => DEFAULT_SEARCH_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_MEMBERS_LIMIT).contains(&limit) => limit,
+        Some(_) => return response.status(400).body(bad_request_body()).unwrap(),
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&guilds.search_members(guild_id, &query, limit)) {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body("members"))
+        .unwrap()
+}
+
+fn handle_cache_guild_member(value: &str, user: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let (Some(guild_id), Some(user_id)) = (parse_id(value), parse_id(user)) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    let Some(member) = guilds.member(guild_id, user_id) else {
+        return response.status(404).body(not_found_body("member")).unwrap();
+    };
+
+    if let Ok(serialized) = to_string(&member) {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body("member"))
+        .unwrap()
+}
+
+/// Permissions of a member as returned by the permissions endpoint.
+#[derive(Serialize)]
+struct MemberPermissions {
+    guild: Permissions,
+    channel: Option<Permissions>,
+}
+
+/// Calculate the permissions of a member in a guild and, if requested, in
+/// one of its channels.
+fn handle_cache_permissions(
+    value: &str,
+    user: &str,
+    channel: Option<&str>,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let (Some(guild_id), Some(user_id)) = (parse_id(value), parse_id(user)) else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let channel_id = match channel.map(parse_id) {
+        None => None,
+        Some(Some(channel_id)) => Some(channel_id),
+        Some(None) => return response.status(400).body(bad_request_body()).unwrap(),
+    };
+
+    let Some(guilds) = guild_cache(guild_id, state) else {
+        return response.status(404).body(not_found_body("guild")).unwrap();
+    };
+
+    let cache = guilds.cache();
+    let permissions = cache.permissions();
+
+    let guild = match permissions.root(user_id, guild_id) {
+        Ok(guild) => guild,
+        Err(e) => {
+            let missing = if matches!(e.kind(), RootErrorType::RoleUnavailable { .. }) {
+                "role"
+            } else {
+                "member"
+            };
+
+            return response
+                .status(409)
+                .body(unavailable_body(missing))
+                .unwrap();
+        }
+    };
+
+    let channel = if let Some(channel_id) = channel_id {
+        let in_guild = cache
+            .channel(channel_id)
+            .is_some_and(|channel| channel.guild_id == Some(guild_id));
+
+        if !in_guild {
+            return response
+                .status(404)
+                .body(not_found_body("channel"))
+                .unwrap();
+        }
+
+        // Threads inherit the permission overwrites of their parent channel
+        match permissions.in_channel(user_id, channel_id) {
+            Ok(channel) => Some(channel),
+            Err(e) => {
+                let kind = e.kind();
+
+                if matches!(
+                    kind,
+                    ChannelErrorType::ChannelNotInGuild { .. }
+                        | ChannelErrorType::ChannelUnavailable { .. }
+                ) {
+                    return response
+                        .status(404)
+                        .body(not_found_body("channel"))
+                        .unwrap();
+                }
+
+                let missing = if matches!(kind, ChannelErrorType::RoleUnavailable { .. }) {
+                    "role"
+                } else if matches!(kind, ChannelErrorType::ParentChannelNotPresent { .. }) {
+                    "parent channel"
+                } else {
+                    "member"
+                };
+
+                return response
+                    .status(409)
+                    .body(unavailable_body(missing))
+                    .unwrap();
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Ok(serialized) = to_string(&MemberPermissions { guild, channel }) {
+        return response.body(Full::from(serialized)).unwrap();
+    }
+
+    response
+        .status(503)
+        .body(serialize_fail_body("permissions"))
+        .unwrap()
+}
+
+/// Maximum size of a batch request body in bytes.
+const MAX_BATCH_BODY_SIZE: usize = 1024 * 1024;
+/// Maximum number of lookups in a single batch request.
+const MAX_BATCH_LOOKUPS: usize = 1000;
+
+/// A single lookup in a batch request.
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Lookup {
+    Guild {
+        id: Id<GuildMarker>,
+    },
+    Channel {
+        id: Id<ChannelMarker>,
+    },
+    User {
+        id: Id<UserMarker>,
+    },
+    Member {
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    },
+    Role {
+        id: Id<RoleMarker>,
+    },
+}
+
+/// Entries found for a batch request, keyed by their IDs, and the lookups
+/// that were not found.
+#[derive(Default, Serialize)]
+struct Batch {
+    guilds: HashMap<String, CachedGuild>,
+    channels: HashMap<String, Channel>,
+    users: HashMap<String, User>,
+    members: HashMap<String, HashMap<String, Member>>,
+    roles: HashMap<String, Role>,
+    misses: Vec<Lookup>,
+}
+
+impl Batch {
+    /// Add the entry for a lookup, or record it as a miss if it is not cached.
+    fn resolve(&mut self, lookup: Lookup, state: &State) {
+        match lookup {
+            Lookup::Guild { id } => {
+                let guild = state
+                    .shard_for_guild(id)
+                    .and_then(|shard| shard.guilds.cache().guild(id).map(|guild| guild.clone()));
+
+                if let Some(guild) = guild {
+                    self.guilds.insert(id.to_string(), guild);
+                    return;
+                }
+            }
+            Lookup::Channel { id } => {
+                let channel = channel_cache(id, state)
+                    .and_then(|guilds| guilds.cache().channel(id).map(|channel| channel.clone()));
+
+                if let Some(channel) = channel {
+                    self.channels.insert(id.to_string(), channel);
+                    return;
+                }
+            }
+            Lookup::User { id } => {
+                let user = state
+                    .shards
+                    .iter()
+                    .find_map(|shard| shard.guilds.cache().user(id).map(|user| user.clone()));
+
+                if let Some(user) = user {
+                    self.users.insert(id.to_string(), user);
+                    return;
+                }
+            }
+            Lookup::Member { guild_id, user_id } => {
+                let member = guild_cache(guild_id, state)
+                    .and_then(|guilds| guilds.member(guild_id, user_id));
+
+                if let Some(member) = member {
+                    self.members
+                        .entry(guild_id.to_string())
+                        .or_default()
+                        .insert(user_id.to_string(), member);
+                    return;
+                }
+            }
+            Lookup::Role { id } => {
+                let role = state.shards.iter().find_map(|shard| {
+                    let cache = shard.guilds.cache();
+                    let role = cache.role(id)?;
+
+                    Some(role.value().resource().clone())
+                });
+
+                if let Some(role) = role {
+                    self.roles.insert(id.to_string(), role);
+                    return;
+                }
+            }
+        }
+
+        self.misses.push(lookup);
+    }
+}
+
+/// Resolve a list of lookups from the caches of all shards at once.
+pub async fn handle_cache_batch(
+    request: Request<Incoming>,
+    state: &State,
+) -> Response<Full<Bytes>> {
+    let start = Instant::now();
+    let response = Response::builder().header("Content-Type", "application/json");
+
+    let Ok(body) = Limited::new(request.into_body(), MAX_BATCH_BODY_SIZE)
+        .collect()
+        .await
+    else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    #[cfg_attr(not(feature = "simd-json"), allow(unused_mut))]
+    let mut body = body.to_bytes().to_vec();
+
+    #[cfg(feature = "simd-json")]
+    let lookups: Result<Vec<Lookup>, _> = simd_json::from_slice(&mut body);
+    #[cfg(not(feature = "simd-json"))]
+    let lookups: Result<Vec<Lookup>, _> = serde_json::from_slice(&body);
+
+    let Ok(lookups) = lookups else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    if lookups.len() > MAX_BATCH_LOOKUPS {
+        return response.status(400).body(bad_request_body()).unwrap();
+    }
+
+    let mut batch = Batch::default();
+
+    for lookup in lookups {
+        batch.resolve(lookup, state);
+    }
+
+    let response = if let Ok(serialized) = to_string(&batch) {
+        response.body(Full::from(serialized)).unwrap()
+    } else {
+        response
+            .status(503)
+            .body(serialize_fail_body("batch"))
+            .unwrap()
+    };
+
+    metrics::histogram!("gateway_cache_request_duration_seconds", "endpoint" => "batch")
+        .record(start.elapsed());
+
+    response
+}
@@ -0,0 +1,249 @@
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string_pretty;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string_pretty;
+
+use std::{env::args, error::Error, process::exit, sync::LazyLock};
+
+use crate::config::{Config, CONFIG};
+
+const USAGE: &str = "\
+Usage: gateway-proxy [--config <path>]... [command]
+
+Commands:
+    run                   Run the proxy (default)
+    check-config          Load and validate the config and print the resolved values
+    dry-run               Show the recommended shard count and remaining session starts
+                          without starting any shards
+    print-default-config  Print a config with the default values
+
+Options:
+    -c, --config <path>   Path to a config file in JSON, TOML or YAML format, can be given
+                          multiple times to layer files [default: config.json]
+    -h, --help            Print this help";
+
+const DEFAULT_CONFIG_PATH: &str = "config.json";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    CheckConfig,
+    DryRun,
+    PrintDefaultConfig,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "run" => Some(Self::Run),
+            "check-config" => Some(Self::CheckConfig),
+            "dry-run" => Some(Self::DryRun),
+            "print-default-config" => Some(Self::PrintDefaultConfig),
+            _ => None,
+        }
+    }
+}
+
+pub struct Args {
+    /// Paths to the config files, later ones override earlier ones.
+    pub config: Vec<String>,
+    pub command: Command,
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut config = Vec::new();
+    let mut command = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                exit(0);
+            }
+            "-c" | "--config" => {
+                config.push(args.next().ok_or("--config requires a path")?);
+            }
+            _ => {
+                if let Some(path) = arg.strip_prefix("--config=") {
+                    config.push(path.to_string());
+                } else if let (None, Some(parsed)) = (command, Command::from_name(&arg)) {
+                    command = Some(parsed);
+                } else {
+                    return Err(format!("Unexpected argument {arg}"));
+                }
+            }
+        }
+    }
+
+    if config.is_empty() {
+        config.push(DEFAULT_CONFIG_PATH.to_string());
+    }
+
+    Ok(Args {
+        config,
+        command: command.unwrap_or(Command::Run),
+    })
+}
+
+pub static ARGS: LazyLock<Args> = LazyLock::new(|| match parse(args().skip(1)) {
+    Ok(args) => args,
+    Err(err) => {
+        eprintln!("{err}\n\n{USAGE}");
+        exit(2);
+    }
+});
+
+fn redact(value: &str) -> String {
+    if value.is_empty() {
+        String::new()
+    } else {
+        String::from("<redacted>")
+    }
+}
+
+/// Print the config with all defaults filled in and secrets redacted. Loading
+/// it exits with all problems found if it is invalid.
+pub fn check_config() {
+    let mut config = CONFIG.clone();
+    config.token = redact(&config.token);
+    config.admin_token = config.admin_token.as_deref().map(redact);
+    config.webhook_url = config.webhook_url.as_deref().map(redact);
+
+    // The URL may contain the password
+    if let Some(redis) = &mut config.redis {
+        redis.url = redact(&redis.url);
+    }
+
+    for bot in &mut config.bots {
+        bot.token = redact(&bot.token);
+    }
+
+    for client in &mut config.clients {
+        client.key = redact(&client.key);
+    }
+
+    eprintln!("Config {} is valid", ARGS.config.join(", "));
+    println!("{}", to_string_pretty(&config).unwrap());
+}
+
+pub fn print_default_config() {
+    println!("{}", to_string_pretty(&Config::template()).unwrap());
+}
+
+/// Report what starting the proxy would do for each bot, based on the
+/// `/gateway/bot` endpoint.
+pub async fn dry_run() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let bots = CONFIG.bots();
+
+    for (index, bot) in bots.iter().enumerate() {
+        let client = crate::http_client(&bot.token);
+        let gateway = client.gateway().authed().await?.model().await?;
+        let session = gateway.session_start_limit;
+
+        let shard_count = bot.shards.unwrap_or(gateway.shards);
+        let shards = bot
+            .shard_range(shard_count)
+            .map_err(|e| format!("[{}] {e}", bot.name))?;
+
+        if bots.len() > 1 {
+            if index > 0 {
+                println!();
+            }
+
+            println!("Bot {}:", bot.name);
+        }
+
+        println!("Recommended shards: {}", gateway.shards);
+        println!(
+            "Would start shards {} to {} of {shard_count} total",
+            shards.start,
+            shards.end - 1
+        );
+        println!(
+            "Session starts: {} of {} remaining, reset in {} seconds",
+            session.remaining,
+            session.total,
+            session.reset_after / 1000
+        );
+        println!("Max concurrency: {}", session.max_concurrency);
+
+        if shards.end - shards.start > session.remaining {
+            println!("Warning: not enough session starts remaining to identify all shards");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Args, Command, DEFAULT_CONFIG_PATH};
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        parse(args.iter().map(ToString::to_string))
+    }
+
+    #[test]
+    fn defaults_to_running_with_the_default_config() {
+        let args = parse_args(&[]).unwrap();
+
+        assert_eq!(args.command, Command::Run);
+        assert_eq!(args.config, [DEFAULT_CONFIG_PATH]);
+    }
+
+    #[test]
+    fn selects_the_command() {
+        for (name, command) in [
+            ("run", Command::Run),
+            ("check-config", Command::CheckConfig),
+            ("dry-run", Command::DryRun),
+            ("print-default-config", Command::PrintDefaultConfig),
+        ] {
+            assert_eq!(parse_args(&[name]).unwrap().command, command);
+        }
+
+        let args = parse_args(&["-c", "base.toml", "check-config"]).unwrap();
+        assert_eq!(args.command, Command::CheckConfig);
+    }
+
+    #[test]
+    fn layers_config_paths_in_order() {
+        let args = parse_args(&[
+            "--config",
+            "base.toml",
+            "-c",
+            "bots.yaml",
+            "--config=local.json",
+        ])
+        .unwrap();
+
+        assert_eq!(args.config, ["base.toml", "bots.yaml", "local.json"]);
+    }
+
+    #[test]
+    fn rejects_unexpected_arguments() {
+        assert_eq!(
+            parse_args(&["--verbose"]).err().as_deref(),
+            Some("Unexpected argument --verbose")
+        );
+        assert_eq!(
+            parse_args(&["serve"]).err().as_deref(),
+            Some("Unexpected argument serve")
+        );
+        // Only one command can be given
+        assert_eq!(
+            parse_args(&["run", "dry-run"]).err().as_deref(),
+            Some("Unexpected argument dry-run")
+        );
+    }
+
+    #[test]
+    fn requires_a_config_path() {
+        for flag in ["--config", "-c"] {
+            assert_eq!(
+                parse_args(&["run", flag]).err().as_deref(),
+                Some("--config requires a path")
+            );
+        }
+    }
+}
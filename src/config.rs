@@ -1,33 +1,59 @@
+use figment::{
+    providers::{Env, Format, Json, Toml, Yaml},
+    Error as FigmentError, Figment,
+};
 use futures_util::StreamExt;
 use inotify::{Inotify, WatchMask};
-use serde::Deserialize;
-#[cfg(not(feature = "simd-json"))]
-use serde_json::Error as JsonError;
-#[cfg(feature = "simd-json")]
-use simd_json::Error as JsonError;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
 use tracing_subscriber::{filter::LevelFilter, reload};
 use twilight_cache_inmemory::ResourceType;
 use twilight_gateway::{EventTypeFlags, Intents};
-use twilight_model::gateway::presence::{Activity, Status};
+use twilight_model::{
+    gateway::{
+        payload::outgoing::{update_presence::UpdatePresencePayload, UpdatePresence},
+        presence::{Activity, ActivityType, Status},
+        OpCode,
+    },
+    id::{marker::GuildMarker, Id},
+};
 
 use std::{
+    collections::{HashMap, HashSet},
     env::var,
     fmt::{Display, Formatter, Result as FmtResult},
-    fs::read_to_string,
+    fs::{metadata, read_to_string},
+    net::{IpAddr, SocketAddr},
+    ops::Range,
+    path::Path,
     process::exit,
     str::FromStr,
-    sync::LazyLock,
+    sync::{Arc, LazyLock},
 };
 
-#[derive(Deserialize)]
+use crate::{
+    cli::ARGS,
+    dispatch,
+    state::Bots,
+    validation::{self, Problem},
+};
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
-    #[serde(default = "token_fallback")]
+    #[serde(default)]
     pub token: String,
+    /// File to read the token from if `token` is not set.
+    #[serde(default)]
+    pub token_file: Option<String>,
     pub intents: Intents,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Listeners to serve on, a single one on `port` with all roles if empty.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
     #[serde(default)]
     pub webhook_url: Option<String>,
     #[serde(default)]
@@ -51,9 +77,303 @@ pub struct Config {
     pub externally_accessible_url: String,
     #[serde(default)]
     pub cache: Cache,
+    #[serde(default)]
+    pub queue: Queue,
+    #[serde(default)]
+    pub standby: Option<Standby>,
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// File to read the admin token from if `admin_token` is not set.
+    #[serde(default)]
+    pub admin_token_file: Option<String>,
+    /// Credentials that clients can use instead of the bot token.
+    #[serde(default)]
+    pub clients: Vec<ClientKey>,
+    #[serde(default)]
+    pub auto_reshard_interval: Option<u64>,
+    #[serde(default = "default_ready_threshold")]
+    pub ready_threshold: u8,
+    #[serde(default)]
+    pub stuck_shard_timeout: Option<u64>,
+    /// Bots to run in addition to the one configured by the fields above.
+    #[serde(default)]
+    pub bots: Vec<Bot>,
+    /// Redis Streams that dispatched events are published to.
+    #[serde(default)]
+    pub redis: Option<Redis>,
+}
+
+/// Name of the bot configured by the top-level fields.
+pub const DEFAULT_BOT: &str = "default";
+
+/// A bot application that the proxy runs its own shards, caches and identify
+/// queue for.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Bot {
+    /// Name of the bot in URLs, logs and metrics.
+    pub name: String,
+    #[serde(default)]
+    pub token: String,
+    /// File to read the token from if `token` is not set.
+    #[serde(default)]
+    pub token_file: Option<String>,
+    pub intents: Intents,
+    #[serde(default)]
+    pub shards: Option<u32>,
+    #[serde(default)]
+    pub shard_start: Option<u32>,
+    #[serde(default)]
+    pub shard_end: Option<u32>,
+    #[serde(default)]
+    pub activity: Option<Activity>,
+    #[serde(default = "default_status")]
+    pub status: Status,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(default)]
+    pub queue: Queue,
 }
 
-#[derive(Deserialize, Clone)]
+impl Bot {
+    /// Presence of a shard, with `{{shard}}` in the activity replaced by its ID.
+    pub fn presence(&self, shard_id: u32) -> UpdatePresencePayload {
+        let shard_id = shard_id.to_string();
+
+        let activities = self
+            .activity
+            .clone()
+            .map(|mut activity| {
+                activity.name = activity.name.replace("{{shard}}", &shard_id);
+
+                if activity.kind == ActivityType::Custom {
+                    activity.state = activity
+                        .state
+                        .map(|state| state.replace("{{shard}}", &shard_id));
+                }
+
+                activity
+            })
+            .into_iter()
+            .collect();
+
+        UpdatePresencePayload {
+            activities,
+            afk: false,
+            since: None,
+            status: self.status,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_BOT
+    }
+
+    /// Range of shard IDs that this process runs when the bot has
+    /// `shard_count` shards in total.
+    ///
+    /// Without `shards`, the total is the count recommended by Discord, so
+    /// `shard_start` and `shard_end` can only be checked against it now.
+    pub fn shard_range(&self, shard_count: u32) -> Result<Range<u32>, String> {
+        let shard_start = self.shard_start.unwrap_or(0);
+        let shard_end = self.shard_end.unwrap_or(shard_count);
+
+        if shard_end > shard_count {
+            return Err(format!(
+                "shard_end ({shard_end}) must not be higher than the shard count ({shard_count}), shard_end is exclusive"
+            ));
+        }
+
+        if shard_start >= shard_end {
+            return Err(format!(
+                "shard_start ({shard_start}) must be lower than shard_end ({shard_end}), the shard count is {shard_count}"
+            ));
+        }
+
+        Ok(shard_start..shard_end)
+    }
+}
+
+/// Identify queue that is shared by several proxy processes running shards of
+/// the same bot.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Queue {
+    /// URL of a queue service compatible with twilight's gateway-queue, like
+    /// the one served by another proxy.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Serve the local queue on `/queue` for other processes.
+    #[serde(default)]
+    pub serve: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Standby {
+    /// Lock file that decides which instance runs the shards.
+    pub lock_file: String,
+    /// HTTP base URL of the primary instance.
+    pub primary_url: String,
+    /// Interval in milliseconds for syncing Discord sessions from the primary.
+    #[serde(default = "default_sync_interval")]
+    pub sync_interval: u64,
+    /// Time in milliseconds after which the standby asks the primary to step
+    /// down if its health endpoint stops responding.
+    #[serde(default = "default_failover_timeout")]
+    pub failover_timeout: u64,
+}
+
+/// Endpoints that a listener serves.
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// WebSocket connections of clients.
+    Gateway,
+    /// The cache API.
+    Cache,
+    /// Metrics and shard details.
+    Metrics,
+    /// The admin API, the sessions for standby instances and the shared
+    /// identify queue.
+    Admin,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Listener {
+    /// IPv4 or IPv6 address and port to listen on.
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+    /// Path of a Unix domain socket to listen on instead of an address.
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Permissions of the Unix domain socket in octal, like `"660"`.
+    #[serde(default)]
+    pub mode: Option<String>,
+    #[serde(default = "default_roles")]
+    pub roles: HashSet<Role>,
+    /// Networks that clients may connect from, all if not set.
+    #[serde(default)]
+    pub allow: Option<Vec<IpNet>>,
+    /// Whether connections start with a PROXY protocol v1 or v2 header.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Networks of proxies whose `Forwarded` and `X-Forwarded-For` headers
+    /// are trusted.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Whether connections use TLS if `tls` is configured, only applies to
+    /// TCP listeners.
+    #[serde(default = "default_listener_tls")]
+    pub tls: bool,
+}
+
+impl Listener {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // Clients on IPv4 connecting to a dual-stack socket have mapped addresses
+        let ip = ip.to_canonical();
+
+        self.allow.as_ref().map_or(true, |allow| {
+            allow.iter().any(|network| network.contains(&ip))
+        })
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(&ip))
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+            .as_deref()
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+    }
+}
+
+/// Access of a client to the HTTP endpoints.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpAccess {
+    None,
+    /// Cache, shard and metrics endpoints.
+    #[default]
+    Read,
+    /// Everything, including the admin API.
+    Admin,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ClientKey {
+    /// Name of the client in logs and signed tokens.
+    pub name: String,
+    /// Key sent by the client as token, or used to sign its tokens.
+    pub key: String,
+    /// Shards the client may connect to, all if not set.
+    #[serde(default)]
+    pub shards: Option<HashSet<u32>>,
+    /// Opcodes the client may send to Discord, all if not set.
+    #[serde(default)]
+    pub opcodes: Option<HashSet<u8>>,
+    /// Names of the bots the client may connect to, all if not set.
+    #[serde(default)]
+    pub bots: Option<HashSet<String>>,
+    #[serde(default)]
+    pub http: HttpAccess,
+}
+
+impl ClientKey {
+    pub fn allows_shard(&self, shard_id: u32) -> bool {
+        self.shards
+            .as_ref()
+            .map_or(true, |shards| shards.contains(&shard_id))
+    }
+
+    pub fn allows_bot(&self, bot: &str) -> bool {
+        self.bots.as_ref().map_or(true, |bots| bots.contains(bot))
+    }
+
+    pub fn allows_opcode(&self, op: u8) -> bool {
+        self.opcodes
+            .as_ref()
+            .map_or(true, |opcodes| opcodes.contains(&op))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Redis {
+    /// Connection URL, like `redis://localhost:6379`.
+    pub url: String,
+    /// Key of the stream an event is appended to. `{bot}`, `{shard}` and
+    /// `{event}` are replaced with the bot name, shard ID and event name.
+    #[serde(default = "default_redis_stream")]
+    pub stream: String,
+    /// Names of the events to publish, all dispatched events if not set.
+    #[serde(default)]
+    pub events: Option<HashSet<String>>,
+    /// Approximate number of entries that streams are trimmed to.
+    #[serde(default)]
+    pub max_len: Option<u64>,
+    /// Number of events that are kept while Redis is unavailable, newer
+    /// events are dropped once it is full.
+    #[serde(default = "default_redis_buffer")]
+    pub buffer: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Tls {
+    /// PEM file with the certificate chain of the server.
+    pub cert: String,
+    /// PEM file with the private key of the server.
+    pub key: String,
+    /// PEM file with the CA certificates that client certificates have to be
+    /// signed by. Clients without a certificate are rejected if this is set.
+    #[serde(default)]
+    pub client_ca: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct Cache {
     pub channels: bool,
     pub presences: bool,
@@ -66,6 +386,260 @@ pub struct Cache {
     pub stickers: bool,
     pub users: bool,
     pub voice_states: bool,
+    /// Number of messages cached per channel, `0` disables the message cache.
+    #[serde(default)]
+    pub messages: usize,
+    /// Guilds that members are cached for and how many.
+    #[serde(default)]
+    pub members_scope: Scope,
+    /// Guilds that presences are cached for and how many.
+    #[serde(default)]
+    pub presences_scope: Scope,
+    /// Member count above which only the current member of a guild is cached.
+    #[serde(default)]
+    pub large_guild_threshold: Option<u64>,
+}
+
+/// Restricts a cached resource to some guilds and limits how many entries are
+/// cached per guild.
+#[derive(Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct Scope {
+    /// If set, only these guilds are cached.
+    #[serde(default)]
+    pub allow: Option<HashSet<Id<GuildMarker>>>,
+    /// Guilds that are never cached.
+    #[serde(default)]
+    pub deny: HashSet<Id<GuildMarker>>,
+    #[serde(default)]
+    pub max_per_guild: Option<usize>,
+}
+
+impl Cache {
+    /// Whether only the current member of a guild with this many members is
+    /// cached.
+    pub fn is_large(&self, member_count: Option<u64>) -> bool {
+        self.large_guild_threshold
+            .zip(member_count)
+            .is_some_and(|(threshold, member_count)| member_count > threshold)
+    }
+}
+
+impl Scope {
+    pub fn allows(&self, guild_id: Id<GuildMarker>) -> bool {
+        !self.deny.contains(&guild_id)
+            && self
+                .allow
+                .as_ref()
+                .map_or(true, |allow| allow.contains(&guild_id))
+    }
+
+    /// Whether another entry can be cached for a guild with `cached` entries.
+    pub fn has_room(&self, cached: usize) -> bool {
+        self.max_per_guild.map_or(true, |max| cached < max)
+    }
+}
+
+impl Config {
+    /// The configured listeners, or the default one on `port`.
+    pub fn listeners(&self) -> Vec<Listener> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![Listener {
+            address: Some(SocketAddr::from(([0, 0, 0, 0], self.port))),
+            path: None,
+            mode: None,
+            roles: default_roles(),
+            allow: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            tls: default_listener_tls(),
+        }]
+    }
+
+    /// Config with the default values and placeholders for required fields.
+    pub fn template() -> Self {
+        Self {
+            log_level: default_log_level(),
+            token: String::new(),
+            token_file: None,
+            intents: Intents::GUILDS,
+            port: default_port(),
+            listeners: Vec::new(),
+            webhook_url: None,
+            shards: None,
+            shard_start: None,
+            shard_end: None,
+            activity: None,
+            status: default_status(),
+            support_guild_id: None,
+            backpressure: default_backpressure(),
+            validate_token: default_validate_token(),
+            twilight_http_proxy: None,
+            externally_accessible_url: format!("ws://localhost:{}", default_port()),
+            cache: Cache::default(),
+            queue: Queue::default(),
+            standby: None,
+            tls: None,
+            admin_token: None,
+            admin_token_file: None,
+            clients: Vec::new(),
+            auto_reshard_interval: None,
+            ready_threshold: default_ready_threshold(),
+            stuck_shard_timeout: None,
+            bots: Vec::new(),
+            redis: None,
+        }
+    }
+
+    /// All bots, starting with the one configured by the top-level fields.
+    pub fn bots(&self) -> Vec<Bot> {
+        let default = Bot {
+            name: DEFAULT_BOT.to_string(),
+            token: self.token.clone(),
+            token_file: self.token_file.clone(),
+            intents: self.intents,
+            shards: self.shards,
+            shard_start: self.shard_start,
+            shard_end: self.shard_end,
+            activity: self.activity.clone(),
+            status: self.status,
+            cache: self.cache.clone(),
+            queue: self.queue.clone(),
+        };
+
+        std::iter::once(default)
+            .chain(self.bots.iter().cloned())
+            .collect()
+    }
+
+    /// URL that clients of a bot connect and resume at.
+    pub fn gateway_url(&self, bot: &str) -> String {
+        if bot == DEFAULT_BOT {
+            self.externally_accessible_url.clone()
+        } else {
+            format!(
+                "{}/bots/{bot}",
+                self.externally_accessible_url.trim_end_matches('/')
+            )
+        }
+    }
+
+    /// Names of the fields that differ from `other` and can only be applied by
+    /// restarting the proxy.
+    fn restart_required_changes(&self, other: &Self) -> Vec<&'static str> {
+        let mut changes = Vec::new();
+
+        if self.port != other.port {
+            changes.push("port");
+        }
+
+        if self.listeners != other.listeners {
+            changes.push("listeners");
+        }
+
+        if self.webhook_url != other.webhook_url {
+            changes.push("webhook_url");
+        }
+
+        if self.shards != other.shards {
+            changes.push("shards");
+        }
+
+        if self.shard_start != other.shard_start {
+            changes.push("shard_start");
+        }
+
+        if self.shard_end != other.shard_end {
+            changes.push("shard_end");
+        }
+
+        if self.support_guild_id != other.support_guild_id {
+            changes.push("support_guild_id");
+        }
+
+        if self.backpressure != other.backpressure {
+            changes.push("backpressure");
+        }
+
+        if self.validate_token != other.validate_token {
+            changes.push("validate_token");
+        }
+
+        if self.twilight_http_proxy != other.twilight_http_proxy {
+            changes.push("twilight_http_proxy");
+        }
+
+        if self.externally_accessible_url != other.externally_accessible_url {
+            changes.push("externally_accessible_url");
+        }
+
+        if self.cache != other.cache {
+            changes.push("cache");
+        }
+
+        if self.queue != other.queue {
+            changes.push("queue");
+        }
+
+        if self.standby != other.standby {
+            changes.push("standby");
+        }
+
+        if self.tls != other.tls {
+            changes.push("tls");
+        }
+
+        if self.clients != other.clients {
+            changes.push("clients");
+        }
+
+        if self.admin_token != other.admin_token {
+            changes.push("admin_token");
+        }
+
+        if self.auto_reshard_interval != other.auto_reshard_interval {
+            changes.push("auto_reshard_interval");
+        }
+
+        if self.ready_threshold != other.ready_threshold {
+            changes.push("ready_threshold");
+        }
+
+        if self.stuck_shard_timeout != other.stuck_shard_timeout {
+            changes.push("stuck_shard_timeout");
+        }
+
+        if self.redis != other.redis {
+            changes.push("redis");
+        }
+
+        // Token, intents and presence of the bots are reloaded like the
+        // top-level ones
+        let bots = |config: &Self| {
+            config
+                .bots
+                .iter()
+                .map(|bot| {
+                    (
+                        bot.name.clone(),
+                        bot.shards,
+                        bot.shard_start,
+                        bot.shard_end,
+                        bot.cache.clone(),
+                        bot.queue.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if bots(self) != bots(other) {
+            changes.push("bots");
+        }
+
+        changes
+    }
 }
 
 impl Default for Cache {
@@ -82,6 +656,10 @@ impl Default for Cache {
             stickers: false,
             users: false,
             voice_states: false,
+            messages: 0,
+            members_scope: Scope::default(),
+            presences_scope: Scope::default(),
+            large_guild_threshold: None,
         }
     }
 }
@@ -138,6 +716,13 @@ impl From<Cache> for EventTypeFlags {
             flags |= Self::USER_UPDATE;
         }
 
+        if cache.messages > 0 {
+            flags |= Self::MESSAGE_CREATE
+                | Self::MESSAGE_DELETE
+                | Self::MESSAGE_DELETE_BULK
+                | Self::MESSAGE_UPDATE;
+        }
+
         flags
     }
 }
@@ -190,6 +775,10 @@ impl From<Cache> for ResourceType {
             resource_types |= Self::VOICE_STATE;
         }
 
+        if cache.messages > 0 {
+            resource_types |= Self::MESSAGE;
+        }
+
         resource_types
     }
 }
@@ -202,13 +791,8 @@ const fn default_port() -> u16 {
     7878
 }
 
-fn token_fallback() -> String {
-    if let Ok(token) = var("TOKEN") {
-        token
-    } else {
-        eprintln!("Config Error: token is not present and TOKEN environment variable is not set");
-        exit(1);
-    }
+fn default_roles() -> HashSet<Role> {
+    HashSet::from([Role::Gateway, Role::Cache, Role::Metrics, Role::Admin])
 }
 
 const fn default_status() -> Status {
@@ -223,8 +807,35 @@ const fn default_validate_token() -> bool {
     true
 }
 
+const fn default_listener_tls() -> bool {
+    true
+}
+
+const fn default_sync_interval() -> u64 {
+    1000
+}
+
+const fn default_failover_timeout() -> u64 {
+    10000
+}
+
+const fn default_ready_threshold() -> u8 {
+    100
+}
+
+fn default_redis_stream() -> String {
+    String::from("gateway:{bot}:{shard}")
+}
+
+const fn default_redis_buffer() -> usize {
+    10000
+}
+
 pub enum Error {
-    InvalidConfig(JsonError),
+    InvalidConfig(Box<FigmentError>),
+    Invalid(Vec<Problem>),
+    MissingToken,
+    MissingBotToken(String),
     NotFound(String),
 }
 
@@ -232,30 +843,105 @@ impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
             Self::InvalidConfig(s) => s.fmt(f),
+            Self::Invalid(problems) => {
+                f.write_str("invalid values")?;
+
+                for problem in problems {
+                    f.write_fmt(format_args!("\n  - {problem}"))?;
+                }
+
+                Ok(())
+            }
+            Self::MissingToken => f.write_str(
+                "token is not present and neither token_file nor the TOKEN environment variable is set",
+            ),
+            Self::MissingBotToken(name) => f.write_fmt(format_args!(
+                "token of bot {name} is not present and token_file is not set"
+            )),
             Self::NotFound(s) => f.write_fmt(format_args!("File {s} not found or access denied")),
         }
     }
 }
 
-#[cfg(feature = "simd-json")]
-pub fn load(path: &str) -> Result<Config, Error> {
-    let mut content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = unsafe { simd_json::from_str(&mut content) }.map_err(Error::InvalidConfig)?;
+/// Prefix of the environment variables that override config fields. Nested
+/// fields are separated by `__`, like `GATEWAY_PROXY_CACHE__MEMBERS`.
+const ENV_PREFIX: &str = "GATEWAY_PROXY_";
 
-    Ok(config)
+fn read_secret(path: &str) -> Result<String, Error> {
+    read_to_string(path)
+        .map(|secret| secret.trim().to_string())
+        .map_err(|_| Error::NotFound(path.to_string()))
 }
 
-#[cfg(not(feature = "simd-json"))]
-pub fn load(path: &str) -> Result<Config, Error> {
-    let content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = serde_json::from_str(&content).map_err(Error::InvalidConfig)?;
+/// Load the config files in order, with later files overriding earlier ones
+/// and environment variables overriding all of them.
+///
+/// The format of each file is picked by its extension, `.toml`, `.yaml` or
+/// `.yml` and JSON for everything else.
+///
+/// Problems that are only warnings are returned along with the config.
+pub fn load(paths: &[String]) -> Result<(Config, Vec<Problem>), Error> {
+    let mut figment = Figment::new();
+
+    for path in paths {
+        if metadata(path).is_err() {
+            return Err(Error::NotFound(path.clone()));
+        }
+
+        figment = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => figment.merge(Toml::file_exact(path)),
+            Some("yaml" | "yml") => figment.merge(Yaml::file_exact(path)),
+            _ => figment.merge(Json::file_exact(path)),
+        };
+    }
+
+    let mut config: Config = figment
+        .merge(Env::prefixed(ENV_PREFIX).split("__"))
+        .extract()
+        .map_err(|e| Error::InvalidConfig(Box::new(e)))?;
+
+    if config.token.is_empty() {
+        config.token = match (&config.token_file, var("TOKEN")) {
+            (Some(token_file), _) => read_secret(token_file)?,
+            (None, Ok(token)) => token,
+            (None, Err(_)) => return Err(Error::MissingToken),
+        };
+    }
+
+    for bot in &mut config.bots {
+        if bot.token.is_empty() {
+            bot.token = match &bot.token_file {
+                Some(token_file) => read_secret(token_file)?,
+                None => return Err(Error::MissingBotToken(bot.name.clone())),
+            };
+        }
+    }
+
+    if let (None, Some(admin_token_file)) = (&config.admin_token, &config.admin_token_file) {
+        config.admin_token = Some(read_secret(admin_token_file)?);
+    }
+
+    let (warnings, problems): (Vec<_>, Vec<_>) = validation::validate(&config)
+        .into_iter()
+        .partition(|problem| problem.warning);
+
+    if !problems.is_empty() {
+        return Err(Error::Invalid(problems));
+    }
 
-    Ok(config)
+    Ok((config, warnings))
 }
 
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    match load("config.json") {
-        Ok(config) => config,
+    match load(&ARGS.config) {
+        Ok((config, warnings)) => {
+            // Logging is not set up before the config is loaded
+            for warning in warnings {
+                eprintln!("Config Warning: {warning}");
+            }
+
+            config
+        }
         Err(err) => {
             // Avoid panicking
             eprintln!("Config Error: {err}");
@@ -264,20 +950,42 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     }
 });
 
-pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter, S>) {
+pub async fn watch_config_changes<S>(
+    reload_handle: reload::Handle<LevelFilter, S>,
+    bots: Arc<Bots>,
+) {
     let Ok(inotify) = Inotify::init() else {
-        tracing::error!("Failed to initialize inotify, log-levels cannot be reloaded on the fly");
+        tracing::error!("Failed to initialize inotify, config cannot be reloaded on the fly");
         return;
     };
 
-    if inotify
-        .watches()
-        .add("config.json", WatchMask::MODIFY)
-        .is_err()
-    {
-        tracing::error!("Failed to add inotify watch, log-levels cannot be reloaded on the fly");
-        return;
-    };
+    let mut config_watches = Vec::new();
+
+    for path in &ARGS.config {
+        if let Ok(watch) = inotify.watches().add(path, WatchMask::MODIFY) {
+            config_watches.push(watch);
+        } else {
+            tracing::error!("Failed to add inotify watch, config cannot be reloaded on the fly");
+            return;
+        }
+    }
+
+    let mut certificate_watches = Vec::new();
+
+    if let Some(certificates) = &bots.tls {
+        for directory in certificates.directories() {
+            let mask = WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::CLOSE_WRITE;
+
+            if let Ok(watch) = inotify.watches().add(directory, mask) {
+                certificate_watches.push(watch);
+            } else {
+                tracing::error!(
+                    "Failed to add inotify watch for {}, TLS certificates cannot be reloaded on the fly",
+                    directory.display()
+                );
+            }
+        }
+    }
 
     tracing::debug!("Inotify is initialized");
 
@@ -285,15 +993,259 @@ pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter,
     // This method never returns Err
     let mut events = inotify.into_event_stream(buffer).unwrap();
 
-    while let Some(Ok(_)) = events.next().await {
-        // This currently only supports reloading log-levels
-        if let Ok(config) = load("config.json") {
+    // The config as it is currently applied
+    let mut running = CONFIG.clone();
+
+    // Tasks that are currently rolling the shards of a bot over to a new
+    // token or intents
+    let mut rolling_tasks: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    while let Some(Ok(event)) = events.next().await {
+        if certificate_watches.contains(&event.wd) {
+            if let (Some(certificates), Some(name)) = (&bots.tls, &event.name) {
+                if certificates.is_affected_by(name) {
+                    certificates.reload();
+                }
+            }
+        }
+
+        if !config_watches.contains(&event.wd) {
+            continue;
+        }
+
+        let config = match load(&ARGS.config) {
+            Ok((config, warnings)) => {
+                for warning in warnings {
+                    tracing::warn!("Config was modified: {warning}");
+                }
+
+                config
+            }
+            Err(err) => {
+                tracing::error!("Config was modified, but failed to reload: {err}");
+                continue;
+            }
+        };
+
+        let mut reloaded = Vec::new();
+
+        if config.log_level != running.log_level {
             let _ = reload_handle.modify(|filter| {
                 *filter = LevelFilter::from_str(&config.log_level).unwrap_or(LevelFilter::INFO);
             });
-            tracing::info!("Config was modified, reloaded log-level");
+            reloaded.push(String::from("log_level"));
+        }
+
+        let running_bots = running.bots();
+
+        for bot in config.bots() {
+            // Added bots are only started on restart
+            let (Some(proxy), Some(previous)) = (
+                bots.get(&bot.name),
+                running_bots
+                    .iter()
+                    .find(|previous| previous.name == bot.name),
+            ) else {
+                continue;
+            };
+
+            let state = proxy.state();
+            let prefix = if bot.is_default() {
+                String::new()
+            } else {
+                format!("bots.{}.", bot.name)
+            };
+
+            if bot.activity != previous.activity {
+                reloaded.push(format!("{prefix}activity"));
+            }
+
+            if bot.status != previous.status {
+                reloaded.push(format!("{prefix}status"));
+            }
+
+            if bot.activity != previous.activity || bot.status != previous.status {
+                for shard in &state.shards {
+                    let presence = bot.presence(shard.id);
+
+                    let result = shard.sender.read().unwrap().command(&UpdatePresence {
+                        d: presence.clone(),
+                        op: OpCode::PresenceUpdate,
+                    });
+
+                    if let Err(e) = result {
+                        tracing::warn!(
+                            "[{} Shard {}] Failed to update presence: {e}",
+                            bot.name,
+                            shard.id
+                        );
+                    }
+
+                    *shard.presence.write().unwrap() = Some(presence);
+                }
+
+                proxy.reload_presence(&bot);
+            }
+
+            let token_changed = bot.token != previous.token;
+
+            if token_changed {
+                reloaded.push(format!("{prefix}token"));
+            }
+
+            if bot.intents != previous.intents {
+                reloaded.push(format!("{prefix}intents"));
+            }
+
+            if token_changed || bot.intents != previous.intents {
+                // A newer change supersedes the one that is still being rolled out
+                if let Some(task) = rolling_tasks.remove(&bot.name) {
+                    task.abort();
+                }
+
+                rolling_tasks.insert(
+                    bot.name.clone(),
+                    tokio::spawn(dispatch::roll_shards(proxy.clone(), bot, token_changed)),
+                );
+            }
+        }
+
+        let restart_required = config.restart_required_changes(&running);
+
+        running.token = config.token;
+        running.intents = config.intents;
+        running.log_level = config.log_level;
+        running.activity = config.activity;
+        running.status = config.status;
+
+        for bot in &mut running.bots {
+            if let Some(reloaded) = config.bots.iter().find(|other| other.name == bot.name) {
+                bot.token.clone_from(&reloaded.token);
+                bot.intents = reloaded.intents;
+                bot.activity.clone_from(&reloaded.activity);
+                bot.status = reloaded.status;
+            }
+        }
+
+        if reloaded.is_empty() {
+            tracing::info!("Config was modified, nothing to reload");
         } else {
-            tracing::error!("Config was modified, but failed to reload");
+            tracing::info!("Config was modified, reloaded {}", reloaded.join(", "));
         }
+
+        if !restart_required.is_empty() {
+            tracing::warn!(
+                "Changes to {} require a restart to be applied",
+                restart_required.join(", ")
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use figment::Jail;
+
+    use super::{load, Config, Error};
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        Jail::expect_with(|jail| {
+            jail.clear_env();
+            jail.create_file(
+                "base.toml",
+                r#"
+                    token = "base-token"
+                    intents = 1
+                    log_level = "debug"
+                    port = 7000
+                "#,
+            )?;
+            jail.create_file("port.yaml", "port: 7001")?;
+            jail.create_file("token.json", r#"{"token": "json-token"}"#)?;
+
+            let (config, warnings) = load(&paths(&["base.toml", "port.yaml", "token.json"]))
+                .map_err(|e| e.to_string())?;
+            assert!(warnings.is_empty());
+            assert_eq!(config.port, 7001);
+            assert_eq!(config.token, "json-token");
+            // Fields that are not overridden keep the value of earlier files
+            assert_eq!(config.log_level, "debug");
+
+            let (config, _) =
+                load(&paths(&["port.yaml", "base.toml"])).map_err(|e| e.to_string())?;
+            assert_eq!(config.port, 7000);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn environment_overrides_files() {
+        Jail::expect_with(|jail| {
+            jail.clear_env();
+            jail.create_file(
+                "config.toml",
+                r#"
+                    token = "file-token"
+                    intents = 1
+                    port = 7000
+
+                    [queue]
+                    url = "http://file:7000/queue"
+                "#,
+            )?;
+            jail.set_env("GATEWAY_PROXY_PORT", 7002);
+            jail.set_env("GATEWAY_PROXY_QUEUE__URL", "http://env:7000/queue");
+
+            let (config, _) = load(&paths(&["config.toml"])).map_err(|e| e.to_string())?;
+            assert_eq!(config.port, 7002);
+            assert_eq!(config.queue.url.as_deref(), Some("http://env:7000/queue"));
+            assert_eq!(config.token, "file-token");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reports_missing_files_and_invalid_values() {
+        Jail::expect_with(|jail| {
+            jail.clear_env();
+            jail.create_file("config.json", r#"{"token": "token", "intents": 1}"#)?;
+
+            assert!(matches!(
+                load(&paths(&["config.json", "missing.toml"])),
+                Err(Error::NotFound(path)) if path == "missing.toml"
+            ));
+
+            jail.set_env("GATEWAY_PROXY_READY_THRESHOLD", 101);
+            assert!(matches!(
+                load(&paths(&["config.json"])),
+                Err(Error::Invalid(problems)) if problems[0].field == "ready_threshold"
+            ));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn checks_shard_range_against_shard_count() {
+        let mut bot = Config::template().bots().remove(0);
+        assert_eq!(bot.shard_range(4), Ok(0..4));
+
+        bot.shard_start = Some(2);
+        assert_eq!(bot.shard_range(4), Ok(2..4));
+        assert!(bot.shard_range(2).is_err());
+
+        bot.shard_end = Some(3);
+        assert_eq!(bot.shard_range(4), Ok(2..3));
+        assert!(bot.shard_range(2).is_err());
+
+        bot.shard_start = Some(3);
+        assert!(bot.shard_range(4).is_err());
     }
 }
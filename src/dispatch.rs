@@ -2,24 +2,34 @@ use futures_util::StreamExt;
 use itoa::Buffer;
 #[cfg(feature = "simd-json")]
 use simd_json::prelude::ValueAsMutContainer;
-use tokio::{sync::broadcast, time::Instant};
-use tracing::{debug, info, trace};
+use tokio::{
+    sync::{broadcast, mpsc, oneshot},
+    time::{timeout, Instant},
+};
+use tracing::{debug, info, trace, warn};
 use twilight_gateway::{
-    parse, Event, EventType, EventTypeFlags, Message, Shard, ShardState as ConnectionState,
+    parse, CloseFrame, Config, ConfigBuilder, Event, EventType, EventTypeFlags, Message, Shard,
+    ShardState as ConnectionState,
+};
+use twilight_http::Client;
+use twilight_model::gateway::{
+    event::GatewayEvent as TwilightGatewayEvent, payload::outgoing::UpdatePresence, OpCode,
 };
-use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
 
 use std::{
+    error::Error,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use crate::{
-    config::CONFIG,
+    config::{Bot, CONFIG},
     deserializer::{EventTypeInfo, GatewayEvent, SequenceInfo},
     discord_log::discord_log,
     model::Ready,
-    state::Shard as ShardState,
+    queue::IdentifyQueue,
+    server::INVALID_SESSION,
+    state::{Proxy, Shard as ShardState},
     SHUTDOWN,
 };
 
@@ -27,37 +37,124 @@ pub type BroadcastMessage = (String, Option<SequenceInfo>);
 
 const TEN_SECONDS: Duration = Duration::from_secs(10);
 
+/// Time to wait for a shard to become ready again when rolling it over
+/// to a new configuration.
+const ROLLING_READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands for the task that drives a shard.
+pub enum ShardCommand {
+    /// Replace the shard with one using a new configuration, which
+    /// requires identifying again.
+    Reconfigure {
+        /// Configuration for the new shard.
+        config: Config<IdentifyQueue>,
+        /// Token that clients have to use from now on.
+        token: String,
+        /// HTTP client for the new token.
+        client: Arc<Client>,
+        /// Notified once the new shard is ready.
+        ready_tx: oneshot::Sender<()>,
+    },
+    /// Close the shard and stop driving it.
+    Shutdown,
+}
+
+#[allow(clippy::too_many_lines)]
 pub async fn events(
-    mut shard: Shard,
+    mut shard: Shard<IdentifyQueue>,
     shard_state: Arc<ShardState>,
     shard_id: u32,
     shard_count: u32,
     broadcast_tx: broadcast::Sender<BroadcastMessage>,
-    client: Arc<twilight_http::Client>,
+    mut commands: mpsc::UnboundedReceiver<ShardCommand>,
+    mut client: Arc<Client>,
 ) {
     // This method only wants to relay events while the shard is in a READY state
     // Therefore, we only put events in the queue while we are connected and READY
     let mut is_ready = false;
 
+    // Notified on the next READY after the shard was reconfigured
+    let mut pending_ready_tx = None;
+
     let mut buffer = Buffer::new();
     let shard_id_str = buffer.format(shard_id).to_owned();
 
     let mut last_metrics_update = Instant::now();
+    let mut events_since_update = 0_u64;
+
+    let event_type_flags: EventTypeFlags = shard_state.bot.cache.clone().into();
+    let bot = shard_state.bot.name.clone();
 
-    let event_type_flags: EventTypeFlags = CONFIG.cache.clone().into();
+    let (mut last_connection, mut last_latency) = {
+        let status = shard_state.status.read().unwrap();
+        (status.connection, status.latency)
+    };
 
     loop {
         // Update metrics if the last update was more than 10s ago
         let now = Instant::now();
 
-        if now.duration_since(last_metrics_update) > TEN_SECONDS {
+        let elapsed = now.duration_since(last_metrics_update);
+
+        if elapsed > TEN_SECONDS {
             let latencies = shard.latency().recent();
             let info = shard.state();
-            update_shard_statistics(&shard_id_str, &shard_state, info, latencies);
+            update_shard_statistics(&bot, &shard_id_str, &shard_state, info, latencies);
             last_metrics_update = now;
+
+            shard_state.status.write().unwrap().events_per_second =
+                events_since_update as f64 / elapsed.as_secs_f64();
+            events_since_update = 0;
+        }
+
+        let message = tokio::select! {
+            message = shard.next() => message,
+            Some(command) = commands.recv() => {
+                match command {
+                    ShardCommand::Reconfigure { config, token, client: new_client, ready_tx } => {
+                        info!("[Shard {shard_id_str}/{shard_count}] Switching to new configuration");
+
+                        reconfigure(&mut shard, config).await;
+
+                        *shard_state.sender.write().unwrap() = shard.sender();
+                        *shard_state.token.write().unwrap() = token;
+                        client = new_client;
+                        shard_state.ready.set_not_ready();
+                        shard_state.session.clear();
+                        is_ready = false;
+                        pending_ready_tx = Some(ready_tx);
+
+                        // Clients have to identify again with the new token
+                        let _res = broadcast_tx.send((INVALID_SESSION.to_string(), None));
+                    }
+                    ShardCommand::Shutdown => {
+                        info!("[Shard {shard_id_str}/{shard_count}] Shutting down");
+
+                        close(&mut shard).await;
+                        shard_state.ready.set_not_ready();
+
+                        return;
+                    }
+                }
+
+                continue;
+            }
+        };
+
+        // Only lock the status when it changed, which is rare compared to
+        // how often messages arrive
+        let connection = shard.state();
+        let latency = shard.latency().recent().first().copied();
+
+        if (connection, latency) != (last_connection, last_latency) {
+            let mut status = shard_state.status.write().unwrap();
+            status.connection = connection;
+            status.latency = latency;
+            last_connection = connection;
+            last_latency = latency;
         }
 
-        let payload = match shard.next().await {
+        let payload = match message {
             Some(Ok(Message::Text(payload))) => payload,
             Some(Ok(Message::Close(_))) if SHUTDOWN.load(Ordering::Relaxed) => return,
             Some(Ok(Message::Close(_))) => {
@@ -85,8 +182,13 @@ pub async fn events(
 
         let (op, sequence, event_type) = event.into_parts();
 
+        if let Some(SequenceInfo(sequence, _)) = &sequence {
+            shard_state.session.set_sequence(*sequence);
+        }
+
         if let Some(EventTypeInfo(event_name, _)) = event_type {
-            metrics::counter!("gateway_shard_events", "shard" => shard_id_str.clone(), "event_type" => event_name.to_owned()).increment(1);
+            metrics::counter!("gateway_shard_events", "bot" => bot.clone(), "shard" => shard_id_str.clone(), "event_type" => event_name.to_owned()).increment(1);
+            events_since_update += 1;
 
             if event_name == "READY" {
                 // Use the raw JSON from READY to create a new blank READY
@@ -107,13 +209,37 @@ pub async fn events(
                 // Override resume_gateway_url with the external URI of the proxy
                 ready.d.insert(
                     String::from("resume_gateway_url"),
-                    CONFIG.externally_accessible_url.clone().into(),
+                    CONFIG.gateway_url(&bot).into(),
                 );
 
+                // Keep track of the session so that standby instances can resume it
+                if let Some(session) = shard.session() {
+                    shard_state.session.set(
+                        session.id().to_owned(),
+                        shard.resume_url().map(ToOwned::to_owned),
+                    );
+                }
+
+                // The shard identified with the presence it was created with,
+                // so apply the one from the last config reload instead
+                let presence = shard_state.presence.read().unwrap().clone();
+                if let Some(presence) = presence {
+                    shard.command(&UpdatePresence {
+                        d: presence,
+                        op: OpCode::PresenceUpdate,
+                    });
+                }
+
                 // We don't care if it was already set
                 // since this data is timeless
                 shard_state.ready.set_ready(ready.d);
+                shard_state.status.write().unwrap().ready_at = Some(Instant::now().into_std());
                 is_ready = true;
+
+                if let Some(ready_tx) = pending_ready_tx.take() {
+                    let _res = ready_tx.send(());
+                }
+
                 info!("[Shard {shard_id_str}/{shard_count}] Ready!");
                 discord_log(
                     client.clone(),
@@ -122,6 +248,7 @@ pub async fn events(
                     format!("Shard `{shard_id_str}/{shard_count}` is ready!"),
                 );
             } else if event_name == "RESUMED" {
+                shard_state.status.write().unwrap().resumed_at = Some(Instant::now().into_std());
                 is_ready = true;
                 // discord_log(
                 //     client.clone(),
@@ -136,6 +263,9 @@ pub async fn events(
                 trace!("[Shard {shard_id}] Sending payload to clients: {payload_copy:?}",);
 
                 let _res = broadcast_tx.send((payload_copy, sequence));
+
+                #[cfg(feature = "redis")]
+                crate::streams::publish(&bot, &shard_id_str, event_name, &payload);
             }
         }
 
@@ -159,6 +289,7 @@ pub async fn events(
                         // We can only reset the READY state if we know that we will get a new READY,
                         // which is the case if we can not resume.
                         shard_state.ready.set_not_ready();
+                        shard_state.session.clear();
                     }
                     // Suspend sending events to clients until READY or RESUMED are received.
                     is_ready = false;
@@ -169,7 +300,116 @@ pub async fn events(
     }
 }
 
+/// Close the connection of a shard and replace it with a new one.
+async fn reconfigure(shard: &mut Shard<IdentifyQueue>, config: Config<IdentifyQueue>) {
+    close(shard).await;
+
+    *shard = Shard::with_config(shard.id(), config);
+}
+
+/// Close the connection of a shard and invalidate its session.
+async fn close(shard: &mut Shard<IdentifyQueue>) {
+    shard.close(CloseFrame::NORMAL);
+
+    // Wait for the close frame to be sent before dropping the connection
+    let _res = timeout(TEN_SECONDS, async {
+        while let Some(message) = shard.next().await {
+            if let Ok(Message::Close(_)) = message {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+async fn session_start_limit(
+    client: &Client,
+) -> Result<(u16, u32, Duration, u32), Box<dyn Error + Send + Sync>> {
+    let gateway = client.gateway().authed().await?.model().await?;
+    let session = gateway.session_start_limit;
+
+    Ok((
+        session.max_concurrency,
+        session.remaining,
+        Duration::from_millis(session.reset_after),
+        session.total,
+    ))
+}
+
+/// Re-identify all shards one after another with a new token and intents.
+///
+/// The proxy switches to the new token and intents first, so that shards
+/// started from now on use them. Each shard is then replaced through the
+/// identify queue, and the next one is only started once it is ready again.
+/// Clients of a shard are told to identify again as soon as it has switched.
+pub async fn roll_shards(proxy: Arc<Proxy>, bot: Bot, token_changed: bool) {
+    let state = proxy.state();
+
+    let client = if token_changed {
+        let client = Arc::new(crate::http_client(&bot.token));
+
+        // The new token may belong to a different application with other limits
+        match session_start_limit(&client).await {
+            Ok((max_concurrency, remaining, reset_after, total)) => {
+                state
+                    .queue
+                    .update(max_concurrency, remaining, reset_after, total);
+            }
+            Err(e) => {
+                tracing::error!("Failed to fetch gateway information for the new token: {e}");
+                return;
+            }
+        }
+
+        client
+    } else {
+        proxy.client()
+    };
+
+    proxy.reload_credentials(&bot, client.clone());
+
+    let base_config = ConfigBuilder::new(bot.token.clone(), bot.intents)
+        .queue(state.queue.clone())
+        .build();
+
+    for shard in &state.shards {
+        let shard_config = ConfigBuilder::from(base_config.clone())
+            .presence(bot.presence(shard.id))
+            .build();
+        let (ready_tx, ready_rx) = oneshot::channel();
+
+        *shard.config.write().unwrap() = shard_config.clone();
+
+        let command = ShardCommand::Reconfigure {
+            config: shard_config,
+            token: bot.token.clone(),
+            client: client.clone(),
+            ready_tx,
+        };
+
+        let result = shard.commands.read().unwrap().send(command);
+        if result.is_err() {
+            warn!("[Shard {}] Shard is not running, skipping", shard.id);
+            continue;
+        }
+
+        match timeout(ROLLING_READY_TIMEOUT, ready_rx).await {
+            Ok(Ok(())) => info!("[Shard {}] Switched to new configuration", shard.id),
+            _ => warn!(
+                "[Shard {}] Not ready after switching to new configuration, continuing",
+                shard.id
+            ),
+        }
+    }
+
+    info!(
+        "[{}] All shards switched to the new configuration",
+        bot.name
+    );
+}
+
 pub fn update_shard_statistics(
+    bot: &str,
     shard_id: &str,
     shard_state: &Arc<ShardState>,
     connection_status: ConnectionState,
@@ -186,34 +426,34 @@ pub fn update_shard_statistics(
 
     let latency = latencies.first().map_or(f64::NAN, Duration::as_secs_f64);
 
-    metrics::histogram!("gateway_shard_latency_histogram", "shard" => shard_id.to_string())
+    metrics::histogram!("gateway_shard_latency_histogram", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .record(latency);
     metrics::gauge!(
         "gateway_shard_latency",
-        "shard" => shard_id.to_string()
+        "bot" => bot.to_owned(), "shard" => shard_id.to_string()
     )
     .set(latency);
-    metrics::histogram!("gateway_shard_status", "shard" => shard_id.to_string())
+    metrics::histogram!("gateway_shard_status", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .record(connection_status);
 
     let stats = shard_state.guilds.stats();
 
-    metrics::gauge!("gateway_cache_emojis", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_emojis", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.emojis() as f64);
-    metrics::gauge!("gateway_cache_guilds", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_guilds", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.guilds() as f64);
-    metrics::gauge!("gateway_cache_members", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_members", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.members() as f64);
-    metrics::gauge!("gateway_cache_presences", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_presences", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.presences() as f64);
-    metrics::gauge!("gateway_cache_channels", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_channels", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.channels() as f64);
-    metrics::gauge!("gateway_cache_roles", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_roles", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.roles() as f64);
-    metrics::gauge!("gateway_cache_unavailable_guilds", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_unavailable_guilds", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.unavailable_guilds() as f64);
-    metrics::gauge!("gateway_cache_users", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_users", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.users() as f64);
-    metrics::gauge!("gateway_cache_voice_states", "shard" => shard_id.to_string())
+    metrics::gauge!("gateway_cache_voice_states", "bot" => bot.to_owned(), "shard" => shard_id.to_string())
         .set(stats.voice_states() as f64);
 }
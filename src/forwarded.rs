@@ -0,0 +1,497 @@
+use hyper::{header::FORWARDED, HeaderMap};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::from_utf8,
+};
+
+use crate::config::Listener;
+
+/// Signature that every PROXY protocol v2 header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a PROXY protocol v1 header, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Length of the shortest possible header, `PROXY UNKNOWN\r\n`.
+const MIN_HEADER_LENGTH: usize = 15;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Read a PROXY protocol v1 or v2 header from the start of a connection and
+/// return the address of the client it reports.
+///
+/// Only the header is consumed, the rest of the stream is left untouched.
+/// Headers without an address, like health checks of the load balancer,
+/// resolve to `peer`.
+pub async fn read_proxy_header<S>(stream: &mut S, peer: SocketAddr) -> io::Result<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; MIN_HEADER_LENGTH];
+    stream.read_exact(&mut header).await?;
+
+    if header[..V2_SIGNATURE.len()] == V2_SIGNATURE {
+        let mut length = [header[14], 0];
+        stream.read_exact(&mut length[1..]).await?;
+
+        let mut addresses = vec![0; u16::from_be_bytes(length).into()];
+        stream.read_exact(&mut addresses).await?;
+
+        parse_v2(header[12], header[13], &addresses, peer)
+    } else if header.starts_with(b"PROXY ") {
+        let mut line = header.to_vec();
+
+        // The header is short and has no length prefix, so it is read one byte
+        // at a time to not consume anything after it
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(invalid("PROXY protocol v1 header is too long"));
+            }
+
+            line.push(stream.read_u8().await?);
+        }
+
+        parse_v1(&line[..line.len() - 2], peer)
+    } else {
+        Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// Parse a line like `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443` without the
+/// CRLF.
+fn parse_v1(line: &[u8], peer: SocketAddr) -> io::Result<SocketAddr> {
+    let line = from_utf8(line).map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    match parts.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(peer),
+        _ => return Err(invalid("unknown PROXY protocol v1 family")),
+    }
+
+    let (Some(source), Some(_), Some(port), Some(_), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err(invalid("malformed PROXY protocol v1 header"));
+    };
+
+    let ip = source
+        .parse::<IpAddr>()
+        .map_err(|_| invalid("invalid source address in PROXY protocol v1 header"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| invalid("invalid source port in PROXY protocol v1 header"))?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+    peer: SocketAddr,
+) -> io::Result<SocketAddr> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // LOCAL connections are made by the load balancer itself
+    if version_command & 0x0F == 0 {
+        return Ok(peer);
+    }
+
+    match family >> 4 {
+        // AF_INET: source address, destination address, source port, destination port
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Ok(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Ok(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        // AF_UNSPEC and AF_UNIX carry no address that is useful here
+        0 | 3 => Ok(peer),
+        _ => Err(invalid("malformed PROXY protocol v2 addresses")),
+    }
+}
+
+/// Parse a node of a `Forwarded` header's `for` parameter or an entry of
+/// `X-Forwarded-For`, like `192.0.2.1`, `"[2001:db8::1]:4711"` or
+/// `192.0.2.1:4711`.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+
+    let ip = node
+        .strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .unwrap_or(node);
+
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// Addresses in forwarding headers, from the client to the closest proxy.
+/// `Forwarded` takes precedence over `X-Forwarded-For`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<SocketAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+            })
+        })
+        .collect();
+
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Resolve the address of the client that made a request. Forwarding headers
+/// are only used when the request comes from a trusted proxy, and are walked
+/// back from the closest proxy until the first address that is not trusted.
+pub fn client_addr(headers: &HeaderMap, peer: SocketAddr, listener: &Listener) -> SocketAddr {
+    let mut addr = peer;
+
+    for node in forwarded_for(headers).into_iter().rev() {
+        if !listener.trusts(addr.ip()) {
+            break;
+        }
+
+        // Obfuscated or unknown nodes cannot be traced back any further
+        let Some(node) = node else {
+            break;
+        };
+
+        addr = node;
+    }
+
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{header::HeaderValue, HeaderMap};
+
+    use std::{
+        collections::HashSet,
+        net::{Ipv6Addr, SocketAddr},
+    };
+
+    use super::{client_addr, read_proxy_header, V2_SIGNATURE};
+    use crate::config::Listener;
+
+    fn peer() -> SocketAddr {
+        "10.0.0.1:50000".parse().unwrap()
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn listener(trusted_proxies: &[&str]) -> Listener {
+        Listener {
+            address: Some(addr("0.0.0.0:7878")),
+            path: None,
+            mode: None,
+            roles: HashSet::new(),
+            allow: None,
+            proxy_protocol: false,
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|network| network.parse().unwrap())
+                .collect(),
+            tls: true,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+
+        headers
+    }
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&u16::try_from(addresses.len()).unwrap().to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_leaves_the_rest_of_the_stream() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n";
+
+        let client = read_proxy_header(&mut stream, peer()).await.unwrap();
+
+        assert_eq!(client, addr("192.0.2.1:56324"));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+
+        let client = read_proxy_header(&mut stream, peer()).await.unwrap();
+
+        assert_eq!(client, addr("[2001:db8::1]:4711"));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_resolves_to_peer() {
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+
+        assert_eq!(
+            read_proxy_header(&mut stream, peer()).await.unwrap(),
+            peer()
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_malformed_headers() {
+        for header in [
+            "PROXY TCP4 192.0.2.1\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 56324 443 extra\r\n",
+            "PROXY TCP4 not-an-ip 192.0.2.2 56324 443\r\n",
+            "PROXY TCP4 192.0.2.1 192.0.2.2 99999 443\r\n",
+            "PROXY UDP4 192.0.2.1 192.0.2.2 56324 443\r\n",
+        ] {
+            let mut stream = header.as_bytes();
+            assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_headers_without_end() {
+        let line = format!("PROXY TCP4 {}", "1".repeat(200));
+        let mut stream = line.as_bytes();
+
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_header() {
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_ipv4_leaves_the_rest_of_the_stream() {
+        let mut data = v2_header(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB],
+        );
+        data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = data.as_slice();
+
+        let client = read_proxy_header(&mut stream, peer()).await.unwrap();
+
+        assert_eq!(client, addr("192.0.2.1:56324"));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_ipv6() {
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        addresses.extend_from_slice(
+            &"2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        addresses.extend_from_slice(&[0x12, 0x67, 0x01, 0xBB]);
+        let data = v2_header(0x21, 0x21, &addresses);
+        let mut stream = data.as_slice();
+
+        let client = read_proxy_header(&mut stream, peer()).await.unwrap();
+
+        assert_eq!(client, addr("[2001:db8::1]:4711"));
+    }
+
+    #[tokio::test]
+    async fn v2_local_resolves_to_peer() {
+        let data = v2_header(0x20, 0x00, &[]);
+        let mut stream = data.as_slice();
+
+        assert_eq!(
+            read_proxy_header(&mut stream, peer()).await.unwrap(),
+            peer()
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_truncated_headers() {
+        // Announces 12 bytes of addresses but ends after 4
+        let data = v2_header(
+            0x21,
+            0x11,
+            &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB],
+        );
+        let mut stream = &data[..data.len() - 8];
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+
+        // Ends within the fixed part of the header
+        let mut stream = &data[..14];
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_addresses_too_short_for_their_family() {
+        let data = v2_header(0x21, 0x11, &[192, 0, 2, 1]);
+        let mut stream = data.as_slice();
+
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_other_versions() {
+        let data = v2_header(
+            0x11,
+            0x11,
+            &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB],
+        );
+        let mut stream = data.as_slice();
+
+        assert!(read_proxy_header(&mut stream, peer()).await.is_err());
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let headers = headers(&[
+            ("x-forwarded-for", "192.0.2.1"),
+            ("forwarded", "for=192.0.2.1"),
+        ]);
+
+        assert_eq!(client_addr(&headers, peer(), &listener(&[])), peer());
+        assert_eq!(
+            client_addr(&headers, peer(), &listener(&["10.1.0.0/16"])),
+            peer()
+        );
+    }
+
+    #[test]
+    fn uses_headers_from_trusted_peers() {
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+
+        assert_eq!(
+            client_addr(&headers, peer(), &listener(&["10.0.0.0/8"])),
+            addr("192.0.2.1:0")
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_address() {
+        // The client prepended a spoofed address before reaching the proxy
+        let headers = headers(&[("x-forwarded-for", "198.51.100.7, 192.0.2.1, 10.0.0.2")]);
+
+        assert_eq!(
+            client_addr(&headers, peer(), &listener(&["10.0.0.0/8"])),
+            addr("192.0.2.1:0")
+        );
+    }
+
+    #[test]
+    fn stops_at_obfuscated_nodes() {
+        let headers = headers(&[("forwarded", "for=192.0.2.1, for=_hidden")]);
+
+        assert_eq!(
+            client_addr(&headers, peer(), &listener(&["10.0.0.0/8"])),
+            peer()
+        );
+    }
+
+    #[test]
+    fn parses_quoted_ipv6_in_forwarded() {
+        let listener = listener(&["10.0.0.0/8"]);
+
+        let with_port = headers(&[("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)]);
+        assert_eq!(
+            client_addr(&with_port, peer(), &listener),
+            addr("[2001:db8::1]:4711")
+        );
+
+        let without_port =
+            headers(&[("forwarded", r#"for=192.0.2.60, For="[2001:db8:cafe::17]""#)]);
+        assert_eq!(
+            client_addr(&without_port, peer(), &listener),
+            addr("[2001:db8:cafe::17]:0")
+        );
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let headers = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "for=192.0.2.1"),
+        ]);
+
+        assert_eq!(
+            client_addr(&headers, peer(), &listener(&["10.0.0.0/8"])),
+            addr("192.0.2.1:0")
+        );
+    }
+
+    #[test]
+    fn trusts_ipv4_mapped_peers() {
+        let headers = headers(&[("x-forwarded-for", "192.0.2.1")]);
+
+        assert_eq!(
+            client_addr(
+                &headers,
+                addr("[::ffff:10.0.0.1]:50000"),
+                &listener(&["10.0.0.0/8"])
+            ),
+            addr("192.0.2.1:0")
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Response;
+use serde::Serialize;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use crate::{
+    cache::{bad_request_body, not_found_body},
+    config::CONFIG,
+    state::{Proxy, Shard, State},
+};
+
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Health of a shard as returned by `/health/shards/:id`.
+#[derive(Serialize)]
+struct ShardHealth {
+    id: u32,
+    ready: bool,
+    stuck: bool,
+    seconds_not_ready: Option<f64>,
+}
+
+/// Whether a shard has not been ready for longer than `stuck_shard_timeout`.
+fn is_stuck(shard: &Shard) -> bool {
+    match (CONFIG.stuck_shard_timeout, shard.ready.not_ready_for()) {
+        (Some(timeout), Some(not_ready_for)) => not_ready_for.as_secs() >= timeout,
+        _ => false,
+    }
+}
+
+/// The proxy is alive as long as it can respond to requests, regardless of
+/// the state of its shards.
+pub fn get_liveness() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(200)
+        .body(Full::from("OK"))
+        .unwrap()
+}
+
+/// The proxy is ready once at least `ready_threshold` percent of the shards
+/// of the given bots are ready.
+pub fn get_readiness(states: &[State]) -> Response<Full<Bytes>> {
+    let shards = states.iter().flat_map(|state| &state.shards);
+    let total = shards.clone().count();
+    let ready = shards.filter(|shard| shard.ready.is_ready()).count();
+
+    let response = Response::builder();
+
+    if ready * 100 < usize::from(CONFIG.ready_threshold) * total {
+        return response
+            .status(400)
+            .body(Full::from(format!(
+                "Not ready! {ready}/{total} shards ready"
+            )))
+            .unwrap();
+    }
+
+    response.status(200).body(Full::from("OK")).unwrap()
+}
+
+pub fn get_shard_health(value: &str, state: &State) -> Response<Full<Bytes>> {
+    let response = Response::builder().header("Content-Type", "application/json");
+    let Ok(shard_id) = value.parse::<u32>() else {
+        return response.status(400).body(bad_request_body()).unwrap();
+    };
+
+    let Some(shard) = state.shards.iter().find(|shard| shard.id == shard_id) else {
+        return response.status(404).body(not_found_body("shard")).unwrap();
+    };
+
+    let health = ShardHealth {
+        id: shard.id,
+        ready: shard.ready.is_ready(),
+        stuck: is_stuck(shard),
+        seconds_not_ready: shard
+            .ready
+            .not_ready_for()
+            .map(|not_ready_for| not_ready_for.as_secs_f64()),
+    };
+
+    response
+        .status(if health.ready { 200 } else { 400 })
+        .body(Full::from(to_string(&health).unwrap()))
+        .unwrap()
+}
+
+/// Periodically check for shards that have not been ready for longer than
+/// `stuck_shard_timeout` and flag them in the logs and metrics.
+pub async fn watchdog(proxy: Arc<Proxy>) {
+    let mut stuck_shards = HashSet::new();
+
+    loop {
+        sleep(WATCHDOG_INTERVAL).await;
+
+        for shard in &proxy.state().shards {
+            let stuck = is_stuck(shard);
+
+            metrics::gauge!("gateway_shard_stuck", "bot" => proxy.bot().name.clone(), "shard" => shard.id.to_string())
+                .set(f64::from(u8::from(stuck)));
+
+            if stuck && stuck_shards.insert(shard.id) {
+                warn!(
+                    "[{} Shard {}] Not ready for more than {} seconds",
+                    proxy.bot().name,
+                    shard.id,
+                    CONFIG.stuck_shard_timeout.unwrap_or_default()
+                );
+            } else if !stuck && stuck_shards.remove(&shard.id) {
+                info!("[{} Shard {}] No longer stuck", proxy.bot().name, shard.id);
+            }
+        }
+    }
+}
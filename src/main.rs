@@ -11,88 +11,88 @@ use metrics_exporter_prometheus::PrometheusBuilder;
 use mimalloc::MiMalloc;
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::broadcast,
     task::JoinSet,
     time::timeout,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt,
 };
-use twilight_cache_inmemory::InMemoryCache;
-use twilight_gateway::{CloseFrame, ConfigBuilder, Shard, ShardId};
-use twilight_gateway_queue::InMemoryQueue;
+use twilight_gateway::{CloseFrame, ConfigBuilder};
 use twilight_http::Client;
-use twilight_model::gateway::{
-    payload::outgoing::update_presence::UpdatePresencePayload, presence::ActivityType,
-};
 
 use std::{
-    collections::HashMap,
     error::Error,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, RwLock,
+        Arc,
     },
     time::Duration,
 };
 
-use crate::config::CONFIG;
+use crate::{
+    cli::Command,
+    config::{Bot, CONFIG},
+    queue::IdentifyQueue,
+};
 use discord_log::discord_log;
 
+mod admin;
+mod auth;
 mod cache;
+mod cli;
 mod config;
 mod deserializer;
 mod discord_log;
 mod dispatch;
+mod forwarded;
+mod health;
+mod messages;
 mod model;
+mod queue;
+mod search;
 mod server;
+mod sharding;
+mod standby;
 mod state;
+#[cfg(feature = "redis")]
+mod streams;
+mod tls;
 mod upgrade;
+mod validation;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
 static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
-#[allow(
-    clippy::cognitive_complexity,
-    clippy::too_many_lines,
-    clippy::redundant_pub_crate
-)]
-async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
-    let level_filter = LevelFilter::from_str(&CONFIG.log_level).unwrap_or(LevelFilter::INFO);
-    let (reload_level_filter, reload_handle) = reload::Layer::new(level_filter);
-    let fmt_layer = tracing_subscriber::fmt::layer();
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(reload_level_filter)
-        .init();
-
-    tokio::spawn(config::watch_config_changes(reload_handle));
-
-    // Set up metrics collection
-    let metrics_handle = PrometheusBuilder::new().install_recorder().unwrap();
-
-    // Set up a HTTPClient
-    let mut client_builder = Client::builder().token(CONFIG.token.clone());
+fn http_client(token: &str) -> Client {
+    let mut client_builder = Client::builder().token(token.to_owned());
 
     if let Some(http_proxy) = CONFIG.twilight_http_proxy.clone() {
         client_builder = client_builder.proxy(http_proxy, true);
     }
 
-    let client = Arc::new(client_builder.build());
+    client_builder.build()
+}
+
+/// Create the shards of a bot without connecting them yet.
+async fn create_bot(bot: Bot) -> Result<state::Proxy, Box<dyn Error + Send + Sync>> {
+    let bot = Arc::new(bot);
+    let client = Arc::new(http_client(&bot.token));
 
     // Check total shards required
     let gateway = client.gateway().authed().await?.model().await?;
 
     let session = gateway.session_start_limit;
 
-    let shard_count = CONFIG.shards.unwrap_or(gateway.shards);
+    let shard_count = bot.shards.unwrap_or(gateway.shards);
 
-    // Set up a queue for the shards
-    let queue = InMemoryQueue::new(
+    // Set up a queue for the shards, every bot has its own session start limit
+    // unless the queue is shared with other processes
+    let queue = IdentifyQueue::new(
+        &bot.queue,
         session.max_concurrency,
         session.remaining,
         Duration::from_millis(session.reset_after),
@@ -100,12 +100,16 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     );
 
     // Create all shards
-    let shard_start = CONFIG.shard_start.unwrap_or(0);
-    let shard_end = CONFIG.shard_end.unwrap_or(shard_count);
+    let shards = bot
+        .shard_range(shard_count)
+        .map_err(|e| format!("[{}] {e}", bot.name))?;
+    let (shard_start, shard_end) = (shards.start, shards.end);
     let shard_end_inclusive = shard_end - 1;
-    let mut shards = Vec::with_capacity((shard_end - shard_start) as usize);
 
-    info!("Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total",);
+    info!(
+        "[{}] Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total",
+        bot.name
+    );
     discord_log(
         client.clone(),
         0x0060_7d8b,
@@ -113,135 +117,198 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         "",
     );
 
-    let config = ConfigBuilder::new(CONFIG.token.clone(), CONFIG.intents)
+    let channel_guilds = Arc::default();
+    let guild_caches = sharding::guild_caches(shard_start..shard_end, &channel_guilds, &bot.cache);
+
+    let config = ConfigBuilder::new(bot.token.clone(), bot.intents)
         .queue(queue)
         .build();
 
-    let mut dispatch_tasks = JoinSet::new();
-
-    for shard_id in shard_start..shard_end {
-        let mut builder = ConfigBuilder::from(config.clone());
-
-        if let Some(mut activity) = CONFIG.activity.clone() {
-            // Replace {{shard}} with the actual ID
-            activity.name = activity.name.replace("{{shard}}", &shard_id.to_string());
-            if activity.kind == ActivityType::Custom && activity.state.is_some() {
-                activity.state = Some(
-                    activity
-                        .state
-                        .unwrap()
-                        .replace("{{shard}}", &shard_id.to_string()),
-                );
-            }
-            // Will only error if activities are empty, so we can unwrap
-            builder = builder.presence(
-                UpdatePresencePayload::new(vec![activity], false, None, CONFIG.status).unwrap(),
-            );
-        }
+    let state = sharding::create(
+        &bot,
+        &config,
+        shard_count,
+        shard_start,
+        guild_caches,
+        channel_guilds,
+    );
+
+    Ok(state::Proxy::new(bot, state, JoinSet::new(), client))
+}
+
+#[allow(
+    clippy::cognitive_complexity,
+    clippy::too_many_lines,
+    clippy::redundant_pub_crate
+)]
+async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let level_filter = LevelFilter::from_str(&CONFIG.log_level).unwrap_or(LevelFilter::INFO);
+    let (reload_level_filter, reload_handle) = reload::Layer::new(level_filter);
+    let fmt_layer = tracing_subscriber::fmt::layer();
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(reload_level_filter)
+        .init();
 
-        let shard = Shard::with_config(ShardId::new(shard_id, shard_count), builder.build());
+    // Set up metrics collection
+    let metrics_handle = PrometheusBuilder::new().install_recorder().unwrap();
 
-        // To support multiple listeners on the same shard
-        // we need to make a broadcast channel with the events
-        let (broadcast_tx, _) = broadcast::channel(CONFIG.backpressure);
+    // Load the TLS certificates before any shard is started
+    let tls = match &CONFIG.tls {
+        Some(tls) => Some(tls::Certificates::load(tls).map_err(|e| e.to_string())?),
+        None => None,
+    };
 
-        let cache = Arc::new(
-            InMemoryCache::builder()
-                .resource_types(CONFIG.cache.clone().into())
-                .message_cache_size(0)
-                .build(),
-        );
-        let guild_cache = cache::Guilds::new(cache.clone());
-
-        let ready = state::Ready::new();
-
-        let shard_status = Arc::new(state::Shard {
-            id: shard_id,
-            sender: shard.sender(),
-            events: broadcast_tx.clone(),
-            ready,
-            guilds: guild_cache,
-        });
-
-        // Now pipe the events into the broadcast
-        // and handle state updates for the guild cache
-        // and set the ready event if received
-        dispatch_tasks.spawn(dispatch::events(
-            shard,
-            shard_status.clone(),
-            shard_id,
-            shard_count,
-            broadcast_tx,
-            client.clone(),
-        ));
-
-        shards.push(shard_status);
-
-        debug!("Created shard {shard_id} of {shard_count} total");
+    #[cfg(feature = "redis")]
+    if let Some(redis) = &CONFIG.redis {
+        streams::start(redis)?;
     }
 
-    let state = Arc::new(state::Inner {
-        shards,
-        shard_count,
-        sessions: RwLock::new(HashMap::new()),
-    });
+    let mut proxies = Vec::new();
+
+    for bot in CONFIG.bots() {
+        proxies.push(Arc::new(create_bot(bot).await?));
+    }
+
+    let bots = Arc::new(state::Bots { bots: proxies, tls });
+
+    // Serve health checks and the cache right away, a standby does so while
+    // it mirrors the primary
+    tokio::spawn(server::run(bots.clone(), metrics_handle));
 
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = server::run(CONFIG.port, state_clone, metrics_handle).await {
-            error!("{}", e);
+    let mut leader_lock = None;
+
+    for proxy in &bots.bots {
+        // With a standby configured, only one instance runs the shards at a
+        // time. The other one mirrors its state until it can take over.
+        let mirrored = match &CONFIG.standby {
+            Some(standby) if proxy.bot().is_default() => {
+                let (lock, mirrored) =
+                    standby::elect(standby, &proxy.bot(), &proxy.state()).await?;
+                leader_lock = Some(lock);
+                mirrored
+            }
+            _ => Vec::new(),
+        };
+
+        sharding::start(proxy, &proxy.state(), mirrored);
+    }
+
+    tokio::spawn(config::watch_config_changes(reload_handle, bots.clone()));
+
+    for proxy in &bots.bots {
+        // Discord's recommendation only matters if the shard count is not fixed
+        if let (None, Some(interval)) = (proxy.bot().shards, CONFIG.auto_reshard_interval) {
+            tokio::spawn(sharding::watch_recommended_shards(
+                proxy.clone(),
+                Duration::from_secs(interval),
+            ));
         }
-    });
+
+        if CONFIG.stuck_shard_timeout.is_some() {
+            tokio::spawn(health::watchdog(proxy.clone()));
+        }
+    }
 
     let mut sigint = signal(SignalKind::interrupt()).unwrap();
     let mut sigterm = signal(SignalKind::terminate()).unwrap();
 
+    // A standby that can't reach this instance asks it to step down, so that
+    // both never run the shards at the same time
+    let step_down = async {
+        match &CONFIG.standby {
+            Some(standby) => standby::wait_for_step_down(&standby.lock_file).await,
+            None => std::future::pending().await,
+        }
+    };
+
     tokio::select! {
         _ = sigint.recv() => info!("received SIGINT, shutting down"),
         _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+        () = step_down => warn!("standby asked to take over, shutting down"),
     }
 
     // Set the flag so that event handlers will be able to tell that a GatewayClose is an expected shutdown
     SHUTDOWN.store(true, Ordering::Relaxed);
 
+    // A standby resumes the sessions of the shards, so Discord must not
+    // invalidate them
+    let close_frame = if CONFIG.standby.is_some() {
+        CloseFrame::RESUME
+    } else {
+        CloseFrame::NORMAL
+    };
+
     // Initiate the shutdown for all shards
-    for shard in &state.shards {
-        let _ = shard.sender.close(CloseFrame::NORMAL);
+    for proxy in &bots.bots {
+        for shard in &proxy.state().shards {
+            let _ = shard.sender.read().unwrap().close(close_frame.clone());
+        }
     }
 
     let mut graceful = 0;
-    let mut ungraceful = dispatch_tasks.len();
-
-    // Wait for all shards to shut down, but if we for some reason fail to do so, exit anyways
-    info!("waiting for {ungraceful} active shard dispatching tasks to shut down");
-
-    loop {
-        match timeout(Duration::from_secs(10), dispatch_tasks.join_next()).await {
-            Ok(Some(_)) => {
-                debug!("shard dispatching task shut down");
-                graceful += 1;
-                ungraceful -= 1;
-            } // Shard task shut down
-            Ok(None) => break, // Set is empty, all tasks were graceful
-            Err(_) => {
-                error!("no shard shut down within 10 seconds, force quitting");
-                break;
-            } // No shard shut down in 10s, remaining ones are ungraceful
+    let mut ungraceful = 0;
+
+    for proxy in &bots.bots {
+        let mut dispatch_tasks = std::mem::take(&mut *proxy.dispatch_tasks.lock().unwrap());
+
+        ungraceful += dispatch_tasks.len();
+
+        // Wait for all shards to shut down, but if we for some reason fail to do so, exit anyways
+        info!(
+            "[{}] waiting for {} active shard dispatching tasks to shut down",
+            proxy.bot().name,
+            dispatch_tasks.len()
+        );
+
+        loop {
+            match timeout(Duration::from_secs(10), dispatch_tasks.join_next()).await {
+                Ok(Some(_)) => {
+                    debug!("shard dispatching task shut down");
+                    graceful += 1;
+                    ungraceful -= 1;
+                } // Shard task shut down
+                Ok(None) => break, // Set is empty, all tasks were graceful
+                Err(_) => {
+                    error!("no shard shut down within 10 seconds, force quitting");
+                    break;
+                } // No shard shut down in 10s, remaining ones are ungraceful
+            }
         }
     }
 
     info!("{graceful} shards shut down gracefully, {ungraceful} not gracefully");
 
+    // A standby may only take over once the shards are shut down
+    drop(leader_lock);
+
     Ok(())
 }
 
 fn main() {
-    if let Err(e) = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(run())
-    {
+    let result = match cli::ARGS.command {
+        Command::CheckConfig => {
+            cli::check_config();
+            Ok(())
+        }
+        Command::PrintDefaultConfig => {
+            cli::print_default_config();
+            Ok(())
+        }
+        Command::DryRun | Command::Run => tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                if cli::ARGS.command == Command::DryRun {
+                    cli::dry_run().await
+                } else {
+                    run().await
+                }
+            }),
+    };
+
+    if let Err(e) = result {
         eprintln!("Fatal error: {e}");
     }
 }
@@ -0,0 +1,110 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use twilight_cache_inmemory::{model::CachedMessage, InMemoryCache};
+use twilight_gateway::Event;
+use twilight_model::id::{
+    marker::{ChannelMarker, MessageMarker},
+    Id,
+};
+
+use std::collections::VecDeque;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Edited,
+    Deleted,
+}
+
+/// A message as it was cached before it was edited or deleted.
+#[derive(Clone, Serialize)]
+pub struct PastMessage {
+    pub change: Change,
+    pub message: CachedMessage,
+}
+
+/// Previous versions of edited messages and deleted messages of each channel,
+/// most recent first.
+///
+/// The message cache drops or overwrites messages as soon as it receives the
+/// event, which is usually before clients get to look them up.
+#[derive(Default)]
+pub struct MessageHistory(DashMap<Id<ChannelMarker>, VecDeque<PastMessage>>);
+
+impl MessageHistory {
+    /// Record the cached versions of messages changed by an event, keeping at
+    /// most `limit` per channel. Has to be called before the cache is updated
+    /// with it.
+    pub fn update(&self, cache: &InMemoryCache, event: &Event, limit: usize) {
+        match event {
+            Event::ChannelDelete(channel) => {
+                self.0.remove(&channel.id);
+            }
+            Event::MessageDelete(message_delete) => {
+                self.record(
+                    cache,
+                    message_delete.channel_id,
+                    message_delete.id,
+                    Change::Deleted,
+                    limit,
+                );
+            }
+            Event::MessageDeleteBulk(message_delete_bulk) => {
+                for message_id in &message_delete_bulk.ids {
+                    self.record(
+                        cache,
+                        message_delete_bulk.channel_id,
+                        *message_id,
+                        Change::Deleted,
+                        limit,
+                    );
+                }
+            }
+            Event::MessageUpdate(message_update) => {
+                // Updates are also sent when embeds are resolved, only keep
+                // actual edits
+                let edited = cache
+                    .message(message_update.id)
+                    .is_some_and(|message| message.content() != message_update.content);
+
+                if edited {
+                    self.record(
+                        cache,
+                        message_update.channel_id,
+                        message_update.id,
+                        Change::Edited,
+                        limit,
+                    );
+                }
+            }
+            Event::ThreadDelete(thread) => {
+                self.0.remove(&thread.id);
+            }
+            _ => {}
+        }
+    }
+
+    fn record(
+        &self,
+        cache: &InMemoryCache,
+        channel_id: Id<ChannelMarker>,
+        message_id: Id<MessageMarker>,
+        change: Change,
+        limit: usize,
+    ) {
+        let Some(message) = cache.message(message_id).map(|message| message.clone()) else {
+            return;
+        };
+
+        let mut history = self.0.entry(channel_id).or_default();
+        history.push_front(PastMessage { change, message });
+        history.truncate(limit);
+    }
+
+    pub fn channel(&self, channel_id: Id<ChannelMarker>, limit: usize) -> Vec<PastMessage> {
+        self.0
+            .get(&channel_id)
+            .map(|history| history.iter().take(limit).cloned().collect())
+            .unwrap_or_default()
+    }
+}
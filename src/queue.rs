@@ -0,0 +1,172 @@
+use bytes::Bytes;
+use http_body_util::{Empty, Full};
+use hyper::{header::AUTHORIZATION, Request, Response, StatusCode, Uri};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use tokio::{sync::oneshot, time::sleep};
+use tracing::{debug, warn};
+use twilight_gateway_queue::{InMemoryQueue, Queue};
+
+use std::{error::Error, time::Duration};
+
+use crate::{
+    config::{self, CONFIG},
+    server::query_param,
+};
+
+/// Time to wait before asking the queue service again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Identify queue that is shared with other processes through an HTTP
+/// service compatible with twilight's gateway-queue, which responds to
+/// `GET <url>?shard=<id>` once the shard may identify.
+#[derive(Clone, Debug)]
+pub struct HttpQueue {
+    client: Client<HttpConnector, Empty<Bytes>>,
+    url: String,
+}
+
+impl HttpQueue {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            url: url.to_string(),
+        }
+    }
+
+    async fn wait_for_turn(&self, shard: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let uri: Uri = format!("{}{separator}shard={shard}", self.url).parse()?;
+        let mut request = Request::get(uri);
+
+        // A proxy serving the queue requires admin access, like it does for
+        // standby instances. Standalone queue services ignore the header.
+        if let Some(admin_token) = &CONFIG.admin_token {
+            request = request.header(AUTHORIZATION, format!("Bearer {admin_token}"));
+        }
+
+        let response = self.client.request(request.body(Empty::new())?).await?;
+
+        if !response.status().is_success() {
+            return Err(format!("queue responded with {}", response.status()).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl Queue for HttpQueue {
+    fn enqueue(&self, shard: u32) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let queue = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match queue.wait_for_turn(shard).await {
+                    Ok(()) => {
+                        debug!("[Shard {shard}] Identify queue allowed identifying");
+                        let _res = tx.send(());
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("[Shard {shard}] Failed to wait for identify queue: {e}");
+                    }
+                }
+
+                // The shard gave up waiting, e.g. because it was shut down
+                if tx.is_closed() {
+                    return;
+                }
+
+                sleep(RETRY_DELAY).await;
+            }
+        });
+
+        rx
+    }
+}
+
+/// Identify queue of a bot, either local to this process or shared with
+/// other processes running shards of the same bot.
+#[derive(Clone, Debug)]
+pub enum IdentifyQueue {
+    Local(InMemoryQueue),
+    Remote(HttpQueue),
+}
+
+impl IdentifyQueue {
+    /// Create the queue configured for a bot, with the session start limit
+    /// used if it is local.
+    pub fn new(
+        config: &config::Queue,
+        max_concurrency: u16,
+        remaining: u32,
+        reset_after: Duration,
+        total: u32,
+    ) -> Self {
+        match &config.url {
+            Some(url) => Self::Remote(HttpQueue::new(url)),
+            None => Self::Local(InMemoryQueue::new(
+                max_concurrency,
+                remaining,
+                reset_after,
+                total,
+            )),
+        }
+    }
+
+    /// Apply a new session start limit. A remote queue keeps track of the
+    /// limit itself.
+    pub fn update(&self, max_concurrency: u16, remaining: u32, reset_after: Duration, total: u32) {
+        if let Self::Local(queue) = self {
+            queue.update(max_concurrency, remaining, reset_after, total);
+        }
+    }
+}
+
+impl Queue for IdentifyQueue {
+    fn enqueue(&self, shard: u32) -> oneshot::Receiver<()> {
+        match self {
+            Self::Local(queue) => queue.enqueue(shard),
+            Self::Remote(queue) => queue.enqueue(shard),
+        }
+    }
+}
+
+/// Serve the local identify queue to other processes, responding once the
+/// shard in the `shard` query parameter may identify.
+pub async fn handle_enqueue<B>(
+    request: &Request<B>,
+    queue: &IdentifyQueue,
+    serve: bool,
+) -> Response<Full<Bytes>> {
+    let (true, IdentifyQueue::Local(queue)) = (serve, queue) else {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::from("Not Found"))
+            .unwrap();
+    };
+
+    let Some(Ok(shard)) = query_param(request, "shard").map(str::parse::<u32>) else {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Full::from("Invalid shard ID"))
+            .unwrap();
+    };
+
+    debug!("[Shard {shard}] Enqueued by a peer");
+
+    if queue.enqueue(shard).await.is_err() {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Full::from("Queue was closed, try again"))
+            .unwrap();
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Full::from("OK"))
+        .unwrap()
+}
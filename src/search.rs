@@ -0,0 +1,324 @@
+use dashmap::DashMap;
+use twilight_gateway::Event;
+use twilight_model::{
+    gateway::payload::incoming::GuildCreate,
+    id::{
+        marker::{GuildMarker, UserMarker},
+        Id,
+    },
+    user::User,
+};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Lowercase names of the cached members of a guild.
+#[derive(Default)]
+struct GuildNames {
+    /// Names of each member.
+    members: HashMap<Id<UserMarker>, Vec<String>>,
+    /// Members by each of their names, ordered for prefix searches.
+    names: BTreeMap<String, HashSet<Id<UserMarker>>>,
+}
+
+impl GuildNames {
+    fn insert(&mut self, user_id: Id<UserMarker>, names: Vec<String>) {
+        self.remove(user_id);
+
+        for name in &names {
+            self.names.entry(name.clone()).or_default().insert(user_id);
+        }
+
+        self.members.insert(user_id, names);
+    }
+
+    fn remove(&mut self, user_id: Id<UserMarker>) {
+        let Some(names) = self.members.remove(&user_id) else {
+            return;
+        };
+
+        for name in names {
+            if let Some(user_ids) = self.names.get_mut(&name) {
+                user_ids.remove(&user_id);
+
+                if user_ids.is_empty() {
+                    self.names.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Members with a name starting with the query, followed by members with
+    /// a name containing it, each ordered by user ID so that results are
+    /// stable.
+    fn search(&self, query: &str, limit: usize) -> Vec<Id<UserMarker>> {
+        let mut prefix_matches: Vec<_> = self
+            .names
+            .range(query.to_owned()..)
+            .take_while(|(name, _)| name.starts_with(query))
+            .flat_map(|(_, user_ids)| user_ids.iter().copied())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        prefix_matches.sort_unstable();
+
+        if prefix_matches.len() >= limit {
+            prefix_matches.truncate(limit);
+            return prefix_matches;
+        }
+
+        let found: HashSet<_> = prefix_matches.iter().copied().collect();
+        let mut substring_matches: Vec<_> = self
+            .members
+            .iter()
+            .filter(|(user_id, names)| {
+                !found.contains(*user_id) && names.iter().any(|name| name.contains(query))
+            })
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        substring_matches.sort_unstable();
+
+        prefix_matches.extend(substring_matches);
+        prefix_matches.truncate(limit);
+
+        prefix_matches
+    }
+}
+
+/// Username, global name and nickname of a member, lowercased.
+fn member_names(user: &User, nick: Option<&str>) -> Vec<String> {
+    let mut names: Vec<_> = [Some(user.name.as_str()), user.global_name.as_deref(), nick]
+        .into_iter()
+        .flatten()
+        .map(str::to_lowercase)
+        .collect();
+
+    names.sort_unstable();
+    names.dedup();
+
+    names
+}
+
+/// Index of the names of the cached members of each guild, used to search
+/// members by name.
+#[derive(Default)]
+pub struct MemberNames(DashMap<Id<GuildMarker>, GuildNames>);
+
+impl MemberNames {
+    /// Keep the index in sync with the members of the cache.
+    pub fn update(&self, event: &Event) {
+        match event {
+            Event::GuildCreate(guild_create) => {
+                if let GuildCreate::Available(guild) = &**guild_create {
+                    let mut names = self.0.entry(guild.id).or_default();
+
+                    for member in &guild.members {
+                        names.insert(
+                            member.user.id,
+                            member_names(&member.user, member.nick.as_deref()),
+                        );
+                    }
+                }
+            }
+            Event::GuildDelete(guild_delete) => {
+                self.0.remove(&guild_delete.id);
+            }
+            Event::MemberAdd(member_add) => {
+                let member = &member_add.member;
+
+                self.0.entry(member_add.guild_id).or_default().insert(
+                    member.user.id,
+                    member_names(&member.user, member.nick.as_deref()),
+                );
+            }
+            Event::MemberChunk(chunk) => {
+                let mut names = self.0.entry(chunk.guild_id).or_default();
+
+                for member in &chunk.members {
+                    names.insert(
+                        member.user.id,
+                        member_names(&member.user, member.nick.as_deref()),
+                    );
+                }
+            }
+            Event::MemberRemove(member_remove) => {
+                if let Some(mut names) = self.0.get_mut(&member_remove.guild_id) {
+                    names.remove(member_remove.user.id);
+                }
+            }
+            Event::MemberUpdate(member_update) => {
+                // Members that are not cached are not indexed either
+                if let Some(mut names) = self.0.get_mut(&member_update.guild_id) {
+                    if names.members.contains_key(&member_update.user.id) {
+                        names.insert(
+                            member_update.user.id,
+                            member_names(&member_update.user, member_update.nick.as_deref()),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Search the members of a guild by username, global name and nickname,
+    /// ignoring case.
+    pub fn search(
+        &self,
+        guild_id: Id<GuildMarker>,
+        query: &str,
+        limit: usize,
+    ) -> Vec<Id<UserMarker>> {
+        let query = query.to_lowercase();
+
+        self.0
+            .get(&guild_id)
+            .map(|names| names.search(&query, limit))
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_gateway::Event;
+    use twilight_model::{
+        gateway::payload::incoming::{MemberAdd, MemberRemove, MemberUpdate},
+        guild::{Member, MemberFlags},
+        id::{
+            marker::{GuildMarker, UserMarker},
+            Id,
+        },
+        user::User,
+    };
+
+    use super::{member_names, GuildNames, MemberNames};
+
+    const GUILD_ID: Id<GuildMarker> = Id::new(1);
+
+    fn user(id: u64, name: &str) -> User {
+        User {
+            accent_color: None,
+            avatar: None,
+            avatar_decoration: None,
+            avatar_decoration_data: None,
+            banner: None,
+            bot: false,
+            discriminator: 0,
+            email: None,
+            flags: None,
+            global_name: None,
+            id: Id::new(id),
+            locale: None,
+            mfa_enabled: None,
+            name: name.to_string(),
+            premium_type: None,
+            public_flags: None,
+            system: None,
+            verified: None,
+        }
+    }
+
+    fn member_add(user: User, nick: Option<&str>) -> Event {
+        Event::MemberAdd(Box::new(MemberAdd {
+            guild_id: GUILD_ID,
+            member: Member {
+                avatar: None,
+                communication_disabled_until: None,
+                deaf: false,
+                flags: MemberFlags::empty(),
+                joined_at: None,
+                mute: false,
+                nick: nick.map(ToString::to_string),
+                pending: false,
+                premium_since: None,
+                roles: Vec::new(),
+                user,
+            },
+        }))
+    }
+
+    fn member_update(user: User, nick: Option<&str>) -> Event {
+        Event::MemberUpdate(Box::new(MemberUpdate {
+            avatar: None,
+            communication_disabled_until: None,
+            guild_id: GUILD_ID,
+            flags: None,
+            deaf: None,
+            joined_at: None,
+            mute: None,
+            nick: nick.map(ToString::to_string),
+            pending: false,
+            premium_since: None,
+            roles: Vec::new(),
+            user,
+        }))
+    }
+
+    fn ids(user_ids: &[u64]) -> Vec<Id<UserMarker>> {
+        user_ids.iter().copied().map(Id::new).collect()
+    }
+
+    #[test]
+    fn orders_prefix_matches_before_substring_matches() {
+        let mut names = GuildNames::default();
+        names.insert(Id::new(1), vec![String::from("malice")]);
+        names.insert(Id::new(3), vec![String::from("alice")]);
+        names.insert(Id::new(2), vec![String::from("alicia")]);
+        names.insert(Id::new(4), vec![String::from("bob")]);
+
+        assert_eq!(names.search("ali", 10), ids(&[2, 3, 1]));
+        assert_eq!(names.search("ali", 2), ids(&[2, 3]));
+        assert_eq!(names.search("lic", 10), ids(&[1, 2, 3]));
+        assert!(names.search("carol", 10).is_empty());
+    }
+
+    #[test]
+    fn matches_each_member_once() {
+        let mut names = GuildNames::default();
+        names.insert(
+            Id::new(1),
+            vec![String::from("alice"), String::from("alicia")],
+        );
+        names.insert(Id::new(2), vec![String::from("malice")]);
+
+        assert_eq!(names.search("ali", 10), ids(&[1, 2]));
+    }
+
+    #[test]
+    fn ignores_case() {
+        let mut alice = user(1, "Alice");
+        alice.global_name = Some(String::from("ALICE"));
+
+        assert_eq!(member_names(&alice, Some("Ali")), ["ali", "alice"]);
+
+        let names = MemberNames::default();
+        names.update(&member_add(alice, Some("Ali")));
+
+        assert_eq!(names.search(GUILD_ID, "aLiCe", 10), ids(&[1]));
+    }
+
+    #[test]
+    fn follows_member_events() {
+        let names = MemberNames::default();
+        names.update(&member_add(user(1, "alice"), Some("ally")));
+        names.update(&member_add(user(2, "bob"), None));
+
+        assert_eq!(names.search(GUILD_ID, "ally", 10), ids(&[1]));
+
+        // The old nickname is no longer found after a rename
+        names.update(&member_update(user(1, "alice"), Some("queen")));
+        assert!(names.search(GUILD_ID, "ally", 10).is_empty());
+        assert_eq!(names.search(GUILD_ID, "queen", 10), ids(&[1]));
+
+        names.update(&Event::MemberRemove(MemberRemove {
+            guild_id: GUILD_ID,
+            user: user(1, "alice"),
+        }));
+        assert!(names.search(GUILD_ID, "alice", 10).is_empty());
+        assert!(names.search(GUILD_ID, "queen", 10).is_empty());
+        assert_eq!(names.search(GUILD_ID, "bob", 10), ids(&[2]));
+
+        // Updates of members that are not cached do not add them
+        names.update(&member_update(user(3, "carol"), None));
+        assert!(names.search(GUILD_ID, "carol", 10).is_empty());
+    }
+}
@@ -2,46 +2,70 @@ use bytes::Bytes;
 use flate2::{Compress, Compression, FlushCompress, Status};
 use futures_util::{Sink, SinkExt, StreamExt};
 use http_body_util::Full;
-use hyper::{body::Incoming, service::service_fn, Request, Response, StatusCode};
+use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto,
 };
 use itoa::Buffer;
 use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Serialize;
 #[cfg(not(feature = "simd-json"))]
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
 use simd_json::{to_string, OwnedValue};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
+    net::{TcpListener, UnixListener},
     sync::{
         broadcast::error::RecvError,
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        oneshot, Notify,
     },
+    task::JoinSet,
+    time::timeout,
 };
-use tokio_websockets::{Error, Limits, Message, ServerBuilder};
+use tokio_websockets::{CloseCode, Error, Limits, Message, ServerBuilder};
 use tracing::{debug, error, info, trace, warn};
-
-use std::{convert::Infallible, future::ready, net::SocketAddr, sync::Arc};
+use twilight_gateway::ShardState as ConnectionState;
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs::{remove_file, set_permissions, symlink_metadata, Permissions},
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::UnixStream,
+    },
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    cache::{
-        handle_cache_channel, handle_cache_guild, handle_cache_isbotuser, handle_cache_user,
-    },
-    config::CONFIG,
+    admin::{handle_close_session, handle_reshard, handle_sessions, handle_shard_action},
+    auth::{allows_http, audit_failure, Access},
+    cache::{handle_cache_batch, handle_cache_request, not_found_body},
+    config::{HttpAccess, Listener, Role, CONFIG},
     deserializer::{GatewayEvent, SequenceInfo},
+    forwarded::{client_addr, read_proxy_header},
+    health::{get_liveness, get_readiness, get_shard_health},
     model::{Identify, Resume},
-    state::{Session, Shard, State},
+    queue::handle_enqueue,
+    state::{Bots, Connection, Proxy, Session, Shard, State},
+    tls::Certificates,
     upgrade,
 };
 
 const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#;
 const HEARTBEAT_ACK: &str = r#"{"t":null,"s":null,"op":11,"d":null}"#;
-const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
+pub const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
 const RESUMED: &str = r#"{"t":"RESUMED","s":null,"op":0,"d":{}}"#;
+pub const RECONNECT: &str = r#"{"t":null,"s":null,"op":7,"d":null}"#;
+
+/// Time that the close frame has to be sent in when closing a connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
 
@@ -102,7 +126,7 @@ where
     while let Some(msg) = message_stream.recv().await {
         trace!("[{addr}] Sending {msg:?}");
 
-        if use_zlib {
+        if use_zlib && !msg.is_close() {
             compression_buffer.clear();
             compress_full(&mut compress, &mut compression_buffer, &msg.into_payload());
 
@@ -178,11 +202,12 @@ async fn forward_shard(
     }
 }
 
-#[allow(clippy::too_many_lines)]
+#[allow(clippy::cognitive_complexity, clippy::too_many_lines)]
 pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     addr: SocketAddr,
     stream: S,
-    state: State,
+    bots: Arc<Bots>,
+    bot: Option<Arc<Proxy>>,
     use_zlib: bool,
 ) -> Result<(), Error> {
     // We use a oneshot channel to tell the forwarding task whether the IDENTIFY
@@ -191,7 +216,9 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     let mut compress_tx = Some(compress_tx);
 
     // We need to know which shard this client is connected to in order to send messages to it
-    let mut shard_sender = None;
+    let mut client_shard: Option<Arc<Shard>> = None;
+    // What the client may do, as determined by its IDENTIFY or RESUME
+    let mut access = Access::Denied;
 
     let ws_conn = ServerBuilder::new()
         .limits(Limits::unlimited())
@@ -202,7 +229,7 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     // Write all messages from a queue to the sink
     let (stream_writer, stream_receiver) = unbounded_channel::<Message>();
 
-    let sink_task = tokio::spawn(sink_from_queue(
+    let mut sink_task = tokio::spawn(sink_from_queue(
         addr,
         use_zlib,
         compress_rx,
@@ -212,7 +239,24 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     let mut shard_forward_task = None;
 
-    while let Some(Ok(msg)) = stream.next().await {
+    // Connections are registered for their session so the admin API can close them
+    let close = Arc::new(Notify::new());
+    let mut connection_session: Option<(State, String)> = None;
+    let mut closed = false;
+
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => msg,
+            () = close.notified() => {
+                closed = true;
+                break;
+            }
+        };
+
+        let Some(Ok(msg)) = msg else {
+            break;
+        };
+
         if !msg.is_text() && !msg.is_binary() {
             continue;
         }
@@ -247,6 +291,14 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     }
                 };
 
+                // Clients that did not connect to the path of a bot are
+                // routed by their token
+                let state = bot
+                    .as_ref()
+                    .or_else(|| bots.by_token(&identify.d.token))
+                    .unwrap_or_else(|| bots.default_bot())
+                    .state();
+
                 let (shard_id, shard_count) = (identify.d.shard[0], identify.d.shard[1]);
 
                 if shard_count != state.shard_count {
@@ -259,10 +311,11 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     break;
                 }
 
-                // Discord tokens may be prefixed by 'Bot ' in IDENTIFY
-                if CONFIG.validate_token
-                    && identify.d.token.split_whitespace().last() != Some(&CONFIG.token)
-                {
+                let shard = state.shards[shard_id as usize].clone();
+
+                access = Access::for_shard(addr, "IDENTIFY", &identify.d.token, &shard);
+
+                if matches!(access, Access::Denied) {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
                     break;
                 }
@@ -277,18 +330,36 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 let session_id = state.create_session(session);
 
                 // The client is connected to this shard, so prepare for sending commands to it
-                let shard = state.shards[shard_id as usize].clone();
-                shard_sender = Some(shard.sender.clone());
+                client_shard = Some(shard.clone());
+
+                state.add_connection(
+                    session_id.clone(),
+                    Connection {
+                        addr,
+                        shard_id,
+                        close: close.clone(),
+                    },
+                );
+                if let Some((previous_state, previous)) =
+                    connection_session.replace((state.clone(), session_id.clone()))
+                {
+                    previous_state.remove_connection(&previous);
+                }
 
-                if let Some(sender) = compress_tx.take() {
-                    shard_forward_task = Some(tokio::spawn(forward_shard(
-                        session_id,
-                        shard,
-                        stream_writer.clone(),
-                        true,
-                        0,
-                    )));
+                // Clients identify again on the same connection after an INVALID_SESSION
+                if let Some(task) = shard_forward_task.take() {
+                    task.abort();
+                }
 
+                shard_forward_task = Some(tokio::spawn(forward_shard(
+                    session_id,
+                    shard,
+                    stream_writer.clone(),
+                    true,
+                    0,
+                )));
+
+                if let Some(sender) = compress_tx.take() {
                     let _res = sender.send(identify.d.compress);
                 }
             }
@@ -308,22 +379,42 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     }
                 };
 
-                // Discord tokens may be prefixed by 'Bot ' in RESUME
-                if CONFIG.validate_token
-                    && resume.d.token.split_whitespace().last() != Some(&CONFIG.token)
-                {
-                    warn!("[{addr}] Token from client mismatched, disconnecting");
-                    break;
-                }
-
                 // Find the shard that has the matching session ID
-                if let Some(session) = state.get_session(&resume.d.session_id) {
+                let found = match &bot {
+                    Some(proxy) => {
+                        let state = proxy.state();
+                        let session = state.get_session(&resume.d.session_id);
+                        session.map(|session| (state, session))
+                    }
+                    None => bots.session(&resume.d.session_id),
+                };
+
+                if let Some((state, session)) = found {
+                    let shard = state.shards[session.shard_id as usize].clone();
+
+                    access = Access::for_shard(addr, "RESUME", &resume.d.token, &shard);
+
+                    if matches!(access, Access::Denied) {
+                        warn!("[{addr}] Token from client mismatched, disconnecting");
+                        break;
+                    }
+
                     let session_id = resume.d.session_id;
                     debug!("[{addr}] Successfully resuming session {session_id}",);
 
-                    let shard = state.shards[session.shard_id as usize].clone();
+                    client_shard = Some(shard.clone());
 
                     if let Some(sender) = compress_tx.take() {
+                        state.add_connection(
+                            session_id.clone(),
+                            Connection {
+                                addr,
+                                shard_id: session.shard_id,
+                                close: close.clone(),
+                            },
+                        );
+                        connection_session = Some((state.clone(), session_id.clone()));
+
                         shard_forward_task = Some(tokio::spawn(forward_shard(
                             session_id,
                             shard.clone(),
@@ -340,10 +431,14 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
                 }
             }
-            _ => {
-                if let Some(sender) = &shard_sender {
-                    trace!("[{addr}] Sending {payload:?} to Discord directly");
-                    let _res = sender.send(payload.to_string());
+            op => {
+                if let Some(shard) = &client_shard {
+                    if access.allows_opcode(op) {
+                        trace!("[{addr}] Sending {payload:?} to Discord directly");
+                        let _res = shard.sender.read().unwrap().send(payload.to_string());
+                    } else {
+                        audit_failure(addr, "payload", &format!("opcode {op} is not permitted"));
+                    }
                 } else {
                     warn!("[{addr}] Client attempted to send payload before IDENTIFY",);
                 }
@@ -351,23 +446,62 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
         }
     }
 
-    debug!("[{addr}] Client disconnected");
-
-    sink_task.abort();
+    if let Some((state, session_id)) = connection_session {
+        state.remove_connection(&session_id);
+    }
 
     if let Some(shard_forward_task) = shard_forward_task {
         shard_forward_task.abort();
     }
 
+    if closed {
+        debug!("[{addr}] Closing connection on request");
+
+        // Let the sink send the close frame before dropping the connection
+        let _res = stream_writer.send(Message::close(Some(CloseCode::NORMAL_CLOSURE), ""));
+        drop(stream_writer);
+        let _res = timeout(CLOSE_TIMEOUT, &mut sink_task).await;
+    } else {
+        debug!("[{addr}] Client disconnected");
+    }
+
+    sink_task.abort();
+
     Ok(())
 }
 
-fn handler(
+/// Get the value of a query string parameter of a request.
+pub fn query_param<'a, B>(request: &'a Request<B>, name: &str) -> Option<&'a str> {
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+fn unauthorized() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body(Full::from("Unauthorized"))
+        .unwrap()
+}
+
+async fn handler(
     addr: SocketAddr,
     request: Request<Incoming>,
-    state: State,
+    listener: &Listener,
+    bots: Arc<Bots>,
     metrics: &PrometheusHandle,
 ) -> Response<Full<Bytes>> {
+    let addr = client_addr(request.headers(), addr, listener);
+
+    if !listener.allows(addr.ip()) {
+        audit_failure(addr, "HTTP request", "address is not in the allowlist");
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Full::from("Forbidden"))
+            .unwrap();
+    }
+
     let segments: Vec<&str> = request
         .uri()
         .path()
@@ -375,7 +509,43 @@ fn handler(
         .filter(|s| !s.is_empty())
         .collect();
 
-    match segments[..] {
+    // Paths of bots other than the default one are prefixed with their name
+    let (bot, segments) = match segments[..] {
+        ["bots", name, ref segments @ ..] => {
+            let Some(proxy) = bots.get(name) else {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header("Content-Type", "application/json")
+                    .body(not_found_body("bot"))
+                    .unwrap();
+            };
+
+            (Some(proxy.clone()), segments)
+        }
+        _ => (None, &segments[..]),
+    };
+
+    let proxy = bot.clone().unwrap_or_else(|| bots.default_bot().clone());
+    let state = proxy.state();
+
+    if required_role(segments).is_some_and(|role| !listener.roles.contains(&role)) {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::from("Not Found"))
+            .unwrap();
+    }
+
+    match *segments {
+        ["metrics" | "shard-count" | "shards" | "cache", ..]
+            if !allows_http(&request, addr, HttpAccess::Read) =>
+        {
+            unauthorized()
+        }
+        // Peers send the admin token, session IDs and identify slots must not
+        // be handed to clients that can only read
+        ["standby" | "queue", ..] if !allows_http(&request, addr, HttpAccess::Admin) => {
+            unauthorized()
+        }
         ["metrics"] => Response::builder()
             .status(StatusCode::OK)
             .body(Full::from(metrics.render()))
@@ -389,76 +559,369 @@ fn handler(
                 .body(Full::from(shard_count_str.to_string()))
                 .unwrap()
         }
-        ["health"] => get_health(&state),
-        ["cache", "guild", id] => handle_cache_guild(id, &state),
-        ["cache", "channel", id] => handle_cache_channel(id, &state),
-        ["cache", "user", id] => handle_cache_user(id, &state),
-        ["cache", "is_botuser", id] => handle_cache_isbotuser(id, &state),
+        ["health"] | ["health", "ready"] => {
+            // Without a bot in the path, all of them have to be ready
+            let states: Vec<State> = if bot.is_some() {
+                vec![state]
+            } else {
+                bots.bots.iter().map(|proxy| proxy.state()).collect()
+            };
+
+            get_readiness(&states)
+        }
+        ["health", "live"] => get_liveness(),
+        ["health", "shards", id] => get_shard_health(id, &state),
+        ["shards"] => get_shards(&state),
+        ["standby", "sessions"] => get_standby_sessions(&state),
+        ["queue"] => handle_enqueue(&request, &state.queue, proxy.bot().queue.serve).await,
+        ["cache", "batch"] if request.method() == Method::POST => {
+            handle_cache_batch(request, &state).await
+        }
+        ["cache", ref segments @ ..] => handle_cache_request(segments, &request, &state),
+        ["admin", ..] if !allows_http(&request, addr, HttpAccess::Admin) => unauthorized(),
+        ["admin", "reshard"] if request.method() == Method::POST => handle_reshard(&request, proxy),
+        ["admin", "sessions"] if request.method() == Method::GET => handle_sessions(&state),
+        ["admin", "sessions", id] if request.method() == Method::DELETE => {
+            handle_close_session(id, &state)
+        }
+        ["admin", "shards", id, action @ ("restart" | "resume")]
+            if request.method() == Method::POST =>
+        {
+            handle_shard_action(id, action, &proxy, &state)
+        }
 
         // Usually one would return a 404 here, but we will just provide the websocket
         // upgrade for backwards compatibility.
-        _ => upgrade::server(addr, request, state),
+        _ => upgrade::server(addr, request, bots, bot),
     }
 }
 
-fn get_health(state: &State) -> Response<Full<Bytes>> {
-    let response = Response::builder();
-    for shard in &state.shards {
-        if !shard.ready.is_ready() {
-            return response.status(400).body(Full::from("Not ready!")).unwrap();
-        }
+/// Details of a shard as listed by `/shards`.
+#[derive(Serialize)]
+struct ShardInfo {
+    id: u32,
+    state: &'static str,
+    latency_ms: Option<f64>,
+    ready: bool,
+    guilds: usize,
+    unavailable_guilds: usize,
+    clients: usize,
+    client_addresses: Vec<SocketAddr>,
+    events_per_second: f64,
+    seconds_since_ready: Option<f64>,
+    seconds_since_resumed: Option<f64>,
+}
+
+const fn connection_state_name(state: ConnectionState) -> &'static str {
+    match state {
+        ConnectionState::Active => "active",
+        ConnectionState::Disconnected { .. } => "disconnected",
+        ConnectionState::FatallyClosed { .. } => "fatally_closed",
+        ConnectionState::Identifying => "identifying",
+        ConnectionState::Resuming => "resuming",
+    }
+}
+
+fn get_shards(state: &State) -> Response<Full<Bytes>> {
+    let mut client_addresses: HashMap<u32, Vec<SocketAddr>> = HashMap::new();
+
+    for connection in state.connections.read().unwrap().values() {
+        client_addresses
+            .entry(connection.shard_id)
+            .or_default()
+            .push(connection.addr);
     }
 
-    response
-        .status(200)
-        .body(Full::from("OK".to_string()))
+    let shards: Vec<_> = state
+        .shards
+        .iter()
+        .map(|shard| {
+            let status = *shard.status.read().unwrap();
+            let stats = shard.guilds.stats();
+            let client_addresses = client_addresses.remove(&shard.id).unwrap_or_default();
+
+            ShardInfo {
+                id: shard.id,
+                state: connection_state_name(status.connection),
+                latency_ms: status.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+                ready: shard.ready.is_ready(),
+                guilds: stats.guilds(),
+                unavailable_guilds: stats.unavailable_guilds(),
+                clients: client_addresses.len(),
+                client_addresses,
+                events_per_second: status.events_per_second,
+                seconds_since_ready: status.ready_at.map(|at| at.elapsed().as_secs_f64()),
+                seconds_since_resumed: status.resumed_at.map(|at| at.elapsed().as_secs_f64()),
+            }
+        })
+        .collect();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Full::from(to_string(&shards).unwrap()))
+        .unwrap()
+}
+
+fn get_standby_sessions(state: &State) -> Response<Full<Bytes>> {
+    let sessions: Vec<_> = state
+        .shards
+        .iter()
+        .filter_map(|shard| shard.session.resume_info(shard.id))
+        .collect();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Full::from(to_string(&sessions).unwrap()))
         .unwrap()
 }
 
-pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> Result<(), Error> {
-    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+/// Time that a connection has to send its PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address reported for clients connected through a Unix domain socket.
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// Role that a listener needs to have to serve a path.
+fn required_role(segments: &[&str]) -> Option<Role> {
+    match segments {
+        ["health", ..] => None,
+        ["metrics"] | ["shards"] => Some(Role::Metrics),
+        ["cache", ..] => Some(Role::Cache),
+        ["admin", ..] | ["standby", ..] | ["queue"] => Some(Role::Admin),
+        _ => Some(Role::Gateway),
+    }
+}
+
+/// Serve connections on all configured listeners.
+pub async fn run(bots: Arc<Bots>, metrics_handle: PrometheusHandle) {
+    let mut listeners = JoinSet::new();
+
+    for listener in CONFIG.listeners() {
+        let listener = Arc::new(listener);
+
+        if let Some(path) = listener.path.clone() {
+            listeners.spawn(listen_unix(
+                path,
+                listener,
+                bots.clone(),
+                metrics_handle.clone(),
+            ));
+        } else if let Some(addr) = listener.address {
+            listeners.spawn(listen_tcp(
+                addr,
+                listener,
+                bots.clone(),
+                metrics_handle.clone(),
+            ));
+        }
+    }
+
+    while listeners.join_next().await.is_some() {}
+}
 
-    let listener = match TcpListener::bind(addr).await {
-        Ok(listener) => listener,
+async fn listen_tcp(
+    addr: SocketAddr,
+    listener: Arc<Listener>,
+    bots: Arc<Bots>,
+    metrics_handle: PrometheusHandle,
+) {
+    let tcp_listener = match TcpListener::bind(addr).await {
+        Ok(tcp_listener) => tcp_listener,
         Err(e) => {
-            error!("Failed to bind TCP listener: {e}");
-            return Ok(());
+            error!("Failed to bind TCP listener on {addr}: {e}");
+            return;
         }
     };
 
-    info!("Listening on {addr}");
+    if bots.tls.is_some() && listener.tls {
+        info!("Listening on {addr} with TLS");
+    } else {
+        info!("Listening on {addr}");
+    }
 
     loop {
-        let (conn, addr) = match listener.accept().await {
+        let (mut conn, peer) = match tcp_listener.accept().await {
             Ok((stream, addr)) => (stream, addr),
             Err(e) => {
                 error!("Failed to accept connection: {e}");
-                return Ok(());
+                return;
             }
         };
 
-        trace!("[{addr:?}] New connection");
+        trace!("[{peer:?}] New connection");
 
-        let state = state.clone();
+        let listener = listener.clone();
+        let bots = bots.clone();
         let metrics_handle = metrics_handle.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = auto::Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(
-                    TokioIo::new(conn),
-                    service_fn(move |incoming: Request<Incoming>| {
-                        ready(Ok::<_, Infallible>(handler(
-                            addr,
-                            incoming,
-                            state.clone(),
-                            &metrics_handle,
-                        )))
-                    }),
-                )
-                .await
-            {
-                error!("Error handling connection: {e}");
+            let addr = if listener.proxy_protocol {
+                let Some(addr) = proxy_header(&mut conn, peer).await else {
+                    return;
+                };
+
+                addr
+            } else {
+                peer
+            };
+
+            // Requests from trusted proxies are checked once the forwarding
+            // headers are known
+            if !listener.trusts(addr.ip()) && !listener.allows(addr.ip()) {
+                audit_failure(addr, "connection", "address is not in the allowlist");
+                return;
+            }
+
+            let Some(acceptor) = bots
+                .tls
+                .as_ref()
+                .filter(|_| listener.tls)
+                .map(Certificates::acceptor)
+            else {
+                serve(conn, addr, listener, bots, metrics_handle).await;
+                return;
+            };
+
+            // The handshake happens in the connection's task so that slow
+            // clients do not hold up accepting others
+            match acceptor.accept(conn).await {
+                Ok(stream) => serve(stream, addr, listener, bots, metrics_handle).await,
+                Err(e) => debug!("[{addr:?}] TLS handshake failed: {e}"),
+            }
+        });
+    }
+}
+
+/// Remove a socket left behind by a previous run, which would make binding
+/// fail. Anything else at `path`, including a socket that another process
+/// still listens on, is left alone.
+fn remove_stale_socket(path: &str) -> io::Result<()> {
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    "another process is listening on it",
+                ));
+            }
+
+            remove_file(path)
+        }
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            "it exists and is not a socket",
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn listen_unix(
+    path: String,
+    listener: Arc<Listener>,
+    bots: Arc<Bots>,
+    metrics_handle: PrometheusHandle,
+) {
+    if let Err(e) = remove_stale_socket(&path) {
+        error!("Failed to bind Unix socket {path}: {e}");
+        return;
+    }
+
+    let unix_listener = match UnixListener::bind(&path) {
+        Ok(unix_listener) => unix_listener,
+        Err(e) => {
+            error!("Failed to bind Unix socket {path}: {e}");
+            return;
+        }
+    };
+
+    if let Some(mode) = listener.mode() {
+        if let Err(e) = set_permissions(&path, Permissions::from_mode(mode)) {
+            error!("Failed to set the permissions of {path}: {e}");
+        }
+    }
+
+    info!("Listening on {path}");
+
+    loop {
+        let mut conn = match unix_listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept connection: {e}");
+                return;
             }
+        };
+
+        trace!("[{path}] New connection");
+
+        let listener = listener.clone();
+        let bots = bots.clone();
+        let metrics_handle = metrics_handle.clone();
+
+        tokio::spawn(async move {
+            let addr = if listener.proxy_protocol {
+                let Some(addr) = proxy_header(&mut conn, UNIX_PEER_ADDR).await else {
+                    return;
+                };
+
+                addr
+            } else {
+                UNIX_PEER_ADDR
+            };
+
+            serve(conn, addr, listener, bots, metrics_handle).await;
         });
     }
 }
+
+/// Read the PROXY protocol header of a connection, logging why it is closed
+/// if there is none.
+async fn proxy_header<S>(stream: &mut S, peer: SocketAddr) -> Option<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    match timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream, peer)).await {
+        Ok(Ok(addr)) => Some(addr),
+        Ok(Err(e)) => {
+            debug!("[{peer:?}] Invalid PROXY protocol header: {e}");
+            None
+        }
+        Err(_) => {
+            debug!("[{peer:?}] Timed out waiting for PROXY protocol header");
+            None
+        }
+    }
+}
+
+/// Serve HTTP requests and WebSocket upgrades on a connection.
+async fn serve<S>(
+    stream: S,
+    addr: SocketAddr,
+    listener: Arc<Listener>,
+    bots: Arc<Bots>,
+    metrics_handle: PrometheusHandle,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(
+            TokioIo::new(stream),
+            service_fn(move |incoming: Request<Incoming>| {
+                let listener = listener.clone();
+                let bots = bots.clone();
+                let metrics_handle = metrics_handle.clone();
+
+                async move {
+                    Ok::<_, Infallible>(
+                        handler(addr, incoming, &listener, bots, &metrics_handle).await,
+                    )
+                }
+            }),
+        )
+        .await
+    {
+        error!("Error handling connection: {e}");
+    }
+}
@@ -0,0 +1,366 @@
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinSet,
+    time::{sleep, timeout},
+};
+use tracing::{debug, error, info, warn};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::{Config, ConfigBuilder, Session, Shard, ShardId};
+use twilight_http::Client;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ops::Range,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    cache,
+    config::{Bot, Cache, CONFIG},
+    dispatch::{self, ShardCommand},
+    queue::IdentifyQueue,
+    server::RECONNECT,
+    standby::Mirrored,
+    state::{self, Proxy, State},
+};
+
+/// Time that new shards have to become ready and populate their caches in
+/// before resharding is aborted.
+const RESHARD_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Create empty guild caches for a range of shards.
+pub fn guild_caches(
+    shards: Range<u32>,
+    channel_guilds: &Arc<cache::ChannelGuilds>,
+    config: &Cache,
+) -> Vec<cache::Guilds> {
+    let config = Arc::new(config.clone());
+
+    shards
+        .map(|_| {
+            let cache = Arc::new(
+                InMemoryCache::builder()
+                    .resource_types(config.as_ref().clone().into())
+                    .message_cache_size(config.messages)
+                    .build(),
+            );
+
+            cache::Guilds::new(cache, channel_guilds.clone(), config.clone())
+        })
+        .collect()
+}
+
+/// Create the state of the shards starting at `shard_start`, one for each of
+/// the guild caches, without connecting them. They are connected by
+/// [`start`].
+///
+/// The shards use the token, intents and identify queue of `config` and the
+/// presence of `bot`.
+pub fn create(
+    bot: &Arc<Bot>,
+    config: &Config<IdentifyQueue>,
+    shard_count: u32,
+    shard_start: u32,
+    guild_caches: Vec<cache::Guilds>,
+    channel_guilds: Arc<cache::ChannelGuilds>,
+) -> State {
+    let mut shards = Vec::with_capacity(guild_caches.len());
+    let token = client_token(config);
+
+    for (shard_id, guild_cache) in (shard_start..).zip(guild_caches) {
+        let shard_config = ConfigBuilder::from(config.clone())
+            .presence(bot.presence(shard_id))
+            .build();
+
+        // Nothing drives this shard yet, the sender and command handle are
+        // replaced once a task does
+        let shard = Shard::with_config(ShardId::new(shard_id, shard_count), shard_config.clone());
+        let (commands_tx, _) = mpsc::unbounded_channel();
+
+        // To support multiple listeners on the same shard
+        // we need to make a broadcast channel with the events
+        let (broadcast_tx, _) = broadcast::channel(CONFIG.backpressure);
+
+        shards.push(Arc::new(state::Shard {
+            id: shard_id,
+            bot: bot.clone(),
+            sender: RwLock::new(shard.sender()),
+            commands: RwLock::new(commands_tx),
+            config: RwLock::new(shard_config),
+            token: RwLock::new(token.clone()),
+            events: broadcast_tx,
+            ready: state::Ready::new(),
+            guilds: guild_cache,
+            session: state::DiscordSession::new(),
+            status: RwLock::new(state::Status::new()),
+            presence: RwLock::new(None),
+        }));
+
+        debug!(
+            "[{}] Created shard {shard_id} of {shard_count} total",
+            bot.name
+        );
+    }
+
+    Arc::new(state::Inner {
+        shards,
+        shard_count,
+        queue: config.queue().clone(),
+        sessions: RwLock::new(HashMap::new()),
+        connections: RwLock::new(HashMap::new()),
+        channel_guilds,
+    })
+}
+
+/// Connect the shards of `state` and spawn the tasks driving them.
+///
+/// Shards with mirrored state from a primary resume its sessions, in the
+/// same order as the shards.
+pub fn start(proxy: &Proxy, state: &State, mirrored: Vec<Mirrored>) {
+    let mut mirrored = mirrored.into_iter();
+    let mut dispatch_tasks = proxy.dispatch_tasks.lock().unwrap();
+
+    for shard_state in &state.shards {
+        let mirrored = mirrored.next().unwrap_or_default();
+
+        // Resume the session that was mirrored from the primary, clients can
+        // use the mirrored READY until Discord tells us otherwise
+        if let Some(resume_info) = mirrored.session {
+            if let Some(mut ready_payload) = mirrored.ready {
+                ready_payload.insert(
+                    String::from("resume_gateway_url"),
+                    CONFIG.gateway_url(&proxy.bot().name).into(),
+                );
+                shard_state.ready.set_ready(ready_payload);
+            }
+
+            shard_state
+                .session
+                .set(resume_info.session_id, resume_info.resume_url);
+            shard_state.session.set_sequence(resume_info.sequence);
+
+            debug!(
+                "[{}] Resuming mirrored session of shard {}",
+                proxy.bot().name,
+                shard_state.id
+            );
+        }
+
+        // Now pipe the events into the broadcast
+        // and handle state updates for the guild cache
+        // and set the ready event if received
+        spawn(
+            shard_state,
+            state.shard_count,
+            true,
+            &proxy.client(),
+            &mut dispatch_tasks,
+        );
+    }
+}
+
+/// Replace all shards with a new set of `shard_count` shards.
+///
+/// The new shards are started in the background and only take over once all
+/// of them are ready and have received all of their guilds. Clients of the old
+/// shards are then told to reconnect.
+pub async fn reshard(proxy: Arc<Proxy>, shard_count: u32) {
+    if proxy.resharding.swap(true, Ordering::AcqRel) {
+        warn!("Resharding is already in progress");
+        return;
+    }
+
+    if let Err(e) = try_reshard(&proxy, shard_count).await {
+        error!("Resharding to {shard_count} shards failed: {e}");
+    }
+
+    proxy.resharding.store(false, Ordering::Release);
+}
+
+async fn try_reshard(proxy: &Proxy, shard_count: u32) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let old_shard_count = proxy.state().shard_count;
+
+    if old_shard_count == shard_count {
+        info!("Already running {shard_count} shards, not resharding");
+        return Ok(());
+    }
+
+    let bot = proxy.bot();
+
+    if bot.shard_start.is_some() || bot.shard_end.is_some() {
+        return Err("resharding is not supported when running a range of shards".into());
+    }
+
+    let gateway = proxy.client().gateway().authed().await?.model().await?;
+    let session = gateway.session_start_limit;
+
+    if session.remaining < shard_count {
+        return Err(format!("only {} session starts remaining", session.remaining).into());
+    }
+
+    info!(
+        "[{}] Resharding from {old_shard_count} to {shard_count} shards",
+        bot.name
+    );
+
+    // The new shards take over the config that the old shards were last
+    // reconfigured with and share their identify queue, since both identify
+    // while they overlap. The bot has the activity and status of the last
+    // reload.
+    let current = proxy.state();
+    let Some(config) = current
+        .shards
+        .first()
+        .map(|shard| shard.config.read().unwrap().clone())
+    else {
+        return Err("there are no shards to take the config from".into());
+    };
+
+    let channel_guilds = Arc::default();
+    let state = create(
+        &bot,
+        &config,
+        shard_count,
+        0,
+        guild_caches(0..shard_count, &channel_guilds, &bot.cache),
+        channel_guilds,
+    );
+
+    start(proxy, &state, Vec::new());
+
+    if timeout(RESHARD_TIMEOUT, wait_until_populated(&state))
+        .await
+        .is_err()
+    {
+        stop(&state);
+        return Err("new shards did not become ready in time".into());
+    }
+
+    let old = proxy.replace(state);
+
+    info!(
+        "[{}] Switched to {shard_count} shards, stopping the old shards",
+        bot.name
+    );
+
+    for shard in &old.shards {
+        let _res = shard.events.send((RECONNECT.to_string(), None));
+    }
+
+    stop(&old);
+
+    Ok(())
+}
+
+/// Wait until all shards are ready and none of their guilds are unavailable.
+async fn wait_until_populated(state: &State) {
+    for shard in &state.shards {
+        shard.ready.wait_until_ready().await;
+
+        while shard.guilds.stats().unavailable_guilds() > 0 {
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        debug!("[Shard {}] Ready and populated", shard.id);
+    }
+}
+
+/// Tell the tasks driving the shards to shut them down.
+fn stop(state: &State) {
+    for shard in &state.shards {
+        let _res = shard.commands.read().unwrap().send(ShardCommand::Shutdown);
+    }
+}
+
+/// Start a new task driving a shard whose previous task stopped, e.g.
+/// because Discord closed its connection with a fatal close code.
+///
+/// The shard resumes its last Discord session if `resume` is set and it has
+/// one, otherwise it identifies again.
+pub fn respawn(proxy: &Proxy, shard_count: u32, shard_state: &Arc<state::Shard>, resume: bool) {
+    spawn(
+        shard_state,
+        shard_count,
+        resume,
+        &proxy.client(),
+        &mut proxy.dispatch_tasks.lock().unwrap(),
+    );
+
+    info!(
+        "[Shard {}] Started a new task driving the shard",
+        shard_state.id
+    );
+}
+
+/// Token that clients of a shard with this config have to use.
+fn client_token(config: &Config<IdentifyQueue>) -> String {
+    let token = config.token();
+
+    // twilight prefixes the token with 'Bot '
+    token.strip_prefix("Bot ").unwrap_or(token).to_owned()
+}
+
+/// Create a shard from the config of its state and spawn a task driving it,
+/// replacing the sender and command handle of the state.
+fn spawn(
+    shard_state: &Arc<state::Shard>,
+    shard_count: u32,
+    resume: bool,
+    client: &Arc<Client>,
+    dispatch_tasks: &mut JoinSet<()>,
+) {
+    let config = shard_state.config.read().unwrap().clone();
+    let token = client_token(&config);
+    let mut builder = ConfigBuilder::from(config);
+
+    match shard_state.session.resume_info(shard_state.id) {
+        Some(resume_info) if resume => {
+            builder = builder.session(Session::new(resume_info.sequence, resume_info.session_id));
+
+            if let Some(resume_url) = resume_info.resume_url {
+                builder = builder.resume_url(resume_url);
+            }
+        }
+        _ => shard_state.session.clear(),
+    }
+
+    let shard = Shard::with_config(ShardId::new(shard_state.id, shard_count), builder.build());
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+
+    *shard_state.sender.write().unwrap() = shard.sender();
+    *shard_state.commands.write().unwrap() = commands_tx;
+    *shard_state.token.write().unwrap() = token;
+
+    dispatch_tasks.spawn(dispatch::events(
+        shard,
+        shard_state.clone(),
+        shard_state.id,
+        shard_count,
+        shard_state.events.clone(),
+        commands_rx,
+        client.clone(),
+    ));
+}
+
+/// Shard count recommended by Discord.
+pub async fn recommended_shards(client: &Client) -> Result<u32, Box<dyn Error + Send + Sync>> {
+    Ok(client.gateway().authed().await?.model().await?.shards)
+}
+
+/// Periodically reshard when Discord recommends a different shard count.
+pub async fn watch_recommended_shards(proxy: Arc<Proxy>, interval: Duration) {
+    loop {
+        sleep(interval).await;
+
+        match recommended_shards(&proxy.client()).await {
+            Ok(shard_count) if shard_count != proxy.state().shard_count => {
+                info!("Discord recommends {shard_count} shards");
+                reshard(proxy.clone(), shard_count).await;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to fetch recommended shard count: {e}"),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use bytes::Bytes;
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use http_body_util::{BodyExt, Empty};
+use hyper::{header::AUTHORIZATION, Request, Uri};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use tokio::time::{sleep, timeout};
+use tokio_websockets::{ClientBuilder, Message};
+use tracing::{debug, info, warn};
+use twilight_gateway::{parse, Event, EventTypeFlags};
+use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{read_to_string, File, OpenOptions, TryLockError},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cache,
+    config::{Bot, Standby, CONFIG},
+    deserializer::{EventTypeInfo, GatewayEvent},
+    model::{JsonObject, Ready},
+    state::{ResumeInfo, State},
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Time between attempts to acquire the lock file while on standby, and
+/// between checks for a request to step down while running as primary.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Written to the lock file by a standby that asks the primary to step down.
+const STEP_DOWN_REQUEST: &str = "step-down";
+
+/// State mirrored from the primary for a single shard.
+#[derive(Default)]
+pub struct Mirrored {
+    /// Last READY payload the primary sent for this shard.
+    pub ready: Option<JsonObject>,
+    /// Discord session of this shard at the time of the last sync.
+    pub session: Option<ResumeInfo>,
+}
+
+fn open_lock_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+}
+
+/// Try to acquire the lock file until it succeeds.
+///
+/// Unlike a blocking lock, this stops trying once the future is dropped.
+async fn acquire_lock(file: &File) -> io::Result<()> {
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) => sleep(LOCK_POLL_INTERVAL).await,
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+}
+
+/// Acquire the lock file once the primary releases it, which happens as soon
+/// as the primary process exits. If the primary has been unreachable for
+/// `failover_timeout`, it is asked to step down through the lock file.
+async fn take_over(file: &File, standby: &Standby, health_uri: &Uri) -> io::Result<()> {
+    tokio::select! {
+        res = acquire_lock(file) => return res,
+        () = watch_primary(health_uri, Duration::from_millis(standby.failover_timeout)) => {}
+    }
+
+    warn!(
+        "Primary has been unreachable for {}ms, asking it to step down",
+        standby.failover_timeout
+    );
+
+    // The lock is advisory, so the file can be written while it is held
+    let mut writer = file;
+    writer.write_all(STEP_DOWN_REQUEST.as_bytes())?;
+
+    acquire_lock(file).await
+}
+
+/// Wait until a standby asks this instance to step down as primary because
+/// it has been unreachable.
+pub async fn wait_for_step_down(lock_file: &str) {
+    loop {
+        sleep(LOCK_POLL_INTERVAL).await;
+
+        if read_to_string(lock_file).is_ok_and(|content| content.contains(STEP_DOWN_REQUEST)) {
+            return;
+        }
+    }
+}
+
+/// Decide whether this instance runs the shards of `state`.
+///
+/// If the lock file is already held by another instance, this will mirror the
+/// primary's state into the caches of the shards until the lock is released.
+/// The returned lock file has to be kept open for as long as this instance
+/// runs the shards.
+///
+/// The mirrored state is returned in the same order as the shards. It is
+/// empty if this instance became the primary right away.
+pub async fn elect(
+    standby: &Standby,
+    bot: &Bot,
+    state: &State,
+) -> Result<(File, Vec<Mirrored>), Box<dyn Error + Send + Sync>> {
+    let file = open_lock_file(&standby.lock_file)?;
+
+    match file.try_lock() {
+        Ok(()) => {
+            info!("Acquired {}, running as primary", standby.lock_file);
+
+            // Requests to step down were meant for the previous primary
+            file.set_len(0)?;
+
+            return Ok((file, Vec::new()));
+        }
+        Err(TryLockError::WouldBlock) => {}
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+
+    let primary_url = standby.primary_url.trim_end_matches('/');
+    let Some(scheme_suffix) = primary_url.strip_prefix("http") else {
+        return Err(format!("Invalid primary URL {primary_url}, expected http(s)").into());
+    };
+    let gateway_uri: Uri = format!("ws{scheme_suffix}").parse()?;
+    let sessions_uri: Uri = format!("{primary_url}/standby/sessions").parse()?;
+    let health_uri: Uri = format!("{primary_url}/health/live").parse()?;
+
+    info!(
+        "{} is held by another instance, running as hot standby for {primary_url}",
+        standby.lock_file
+    );
+
+    let mut readies = vec![None; state.shards.len()];
+    let mut sessions = HashMap::new();
+
+    {
+        let mirror = async {
+            tokio::join!(
+                join_all(
+                    state
+                        .shards
+                        .iter()
+                        .zip(readies.iter_mut())
+                        .map(|(shard, ready)| {
+                            mirror_shard(
+                                &gateway_uri,
+                                bot,
+                                shard.id,
+                                state.shard_count,
+                                &shard.guilds,
+                                ready,
+                            )
+                        })
+                ),
+                sync_sessions(
+                    &sessions_uri,
+                    Duration::from_millis(standby.sync_interval),
+                    &mut sessions,
+                ),
+            );
+        };
+
+        tokio::select! {
+            res = take_over(&file, standby, &health_uri) => res?,
+            () = mirror => unreachable!("mirroring the primary never finishes"),
+        }
+    }
+
+    info!("Primary released {}, taking over", standby.lock_file);
+    file.set_len(0)?;
+
+    let mirrored = readies
+        .into_iter()
+        .zip(state.shards.iter().map(|shard| shard.id))
+        .map(|(ready, shard_id)| Mirrored {
+            ready,
+            session: sessions.remove(&shard_id),
+        })
+        .collect();
+
+    Ok((file, mirrored))
+}
+
+/// Mirror the events of a shard by connecting to the primary like any other
+/// client of the bot would.
+async fn mirror_shard(
+    uri: &Uri,
+    bot: &Bot,
+    shard_id: u32,
+    shard_count: u32,
+    guilds: &cache::Guilds,
+    ready: &mut Option<JsonObject>,
+) {
+    let identify = format!(
+        r#"{{"op":2,"d":{{"token":"{}","intents":{},"shard":[{shard_id},{shard_count}],"properties":{{"os":"linux","browser":"gateway-proxy","device":"gateway-proxy"}}}}}}"#,
+        bot.token,
+        bot.intents.bits(),
+    );
+    let event_type_flags: EventTypeFlags = bot.cache.clone().into();
+
+    loop {
+        match ClientBuilder::from_uri(uri.clone()).connect().await {
+            Ok((mut stream, _)) => {
+                debug!("[Shard {shard_id}] Connected to primary");
+
+                if stream.send(Message::text(identify.clone())).await.is_ok() {
+                    while let Some(Ok(msg)) = stream.next().await {
+                        if let Some(payload) = msg.as_text() {
+                            mirror_payload(payload, guilds, ready, event_type_flags);
+                        }
+                    }
+                }
+
+                warn!("[Shard {shard_id}] Lost connection to primary");
+            }
+            Err(e) => warn!("[Shard {shard_id}] Failed to connect to primary: {e}"),
+        }
+
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+fn mirror_payload(
+    payload: &str,
+    guilds: &cache::Guilds,
+    ready: &mut Option<JsonObject>,
+    event_type_flags: EventTypeFlags,
+) {
+    let Some(event) = GatewayEvent::from_json(payload) else {
+        return;
+    };
+
+    let (_, _, event_type) = event.into_parts();
+
+    if let Some(EventTypeInfo("READY", _)) = event_type {
+        #[cfg(feature = "simd-json")]
+        let maybe_ready: Result<Ready, _> = unsafe { simd_json::from_str(&mut payload.to_owned()) };
+        #[cfg(not(feature = "simd-json"))]
+        let maybe_ready: Result<Ready, _> = serde_json::from_str(payload);
+
+        if let Ok(mirrored) = maybe_ready {
+            *ready = Some(mirrored.d);
+        }
+    }
+
+    if let Ok(Some(TwilightGatewayEvent::Dispatch(_, event))) =
+        parse(payload.to_owned(), event_type_flags)
+    {
+        guilds.update(Event::from(event));
+    }
+}
+
+/// Check the health endpoint of the primary until it has not responded
+/// successfully for `failover_timeout`.
+async fn watch_primary(uri: &Uri, failover_timeout: Duration) {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let interval = (failover_timeout / 4).max(Duration::from_millis(100));
+    let mut last_seen = Instant::now();
+
+    loop {
+        let request = Request::get(uri.clone())
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+
+        // A primary that hangs is as good as gone
+        match timeout(interval, client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => last_seen = Instant::now(),
+            Ok(Ok(response)) => warn!("Primary health check responded with {}", response.status()),
+            Ok(Err(e)) => warn!("Primary health check failed: {e}"),
+            Err(_) => warn!("Primary health check timed out"),
+        }
+
+        if last_seen.elapsed() >= failover_timeout {
+            return;
+        }
+
+        sleep(interval).await;
+    }
+}
+
+/// Periodically fetch the Discord sessions of all shards from the primary.
+async fn sync_sessions(uri: &Uri, interval: Duration, sessions: &mut HashMap<u32, ResumeInfo>) {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+
+    loop {
+        match fetch_sessions(&client, uri).await {
+            Ok(fetched) => {
+                *sessions = fetched
+                    .into_iter()
+                    .map(|session| (session.shard_id, session))
+                    .collect();
+            }
+            Err(e) => warn!("Failed to sync sessions from primary: {e}"),
+        }
+
+        sleep(interval).await;
+    }
+}
+
+async fn fetch_sessions(
+    client: &Client<HttpConnector, Empty<Bytes>>,
+    uri: &Uri,
+) -> Result<Vec<ResumeInfo>, Box<dyn Error + Send + Sync>> {
+    let mut request = Request::get(uri.clone());
+
+    // The primary only serves the sessions with admin access
+    if let Some(admin_token) = &CONFIG.admin_token {
+        request = request.header(AUTHORIZATION, format!("Bearer {admin_token}"));
+    }
+
+    let response = client.request(request.body(Empty::new())?).await?;
+
+    if !response.status().is_success() {
+        return Err(format!("Primary responded with {}", response.status()).into());
+    }
+
+    #[cfg_attr(not(feature = "simd-json"), allow(unused_mut))]
+    let mut body = response.into_body().collect().await?.to_bytes().to_vec();
+
+    #[cfg(feature = "simd-json")]
+    let sessions = simd_json::from_slice(&mut body)?;
+    #[cfg(not(feature = "simd-json"))]
+    let sessions = serde_json::from_slice(&body)?;
+
+    Ok(sessions)
+}
@@ -1,18 +1,42 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::sync::{broadcast, Notify};
-use twilight_gateway::MessageSender;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{broadcast, mpsc, Notify},
+    task::JoinSet,
+};
+use twilight_gateway::{Config, MessageSender, ShardState as ConnectionState};
+use twilight_http::Client;
+use twilight_model::{
+    gateway::payload::outgoing::update_presence::UpdatePresencePayload,
+    id::{marker::GuildMarker, Id},
+};
 
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{cache, dispatch::BroadcastMessage, model::JsonObject};
+use crate::{
+    auth::secrets_match,
+    cache,
+    config::{Bot, CONFIG},
+    dispatch::{BroadcastMessage, ShardCommand},
+    model::JsonObject,
+    queue::IdentifyQueue,
+    tls,
+};
 
 /// Manager for the READY state of a shard.
 pub struct Ready {
     inner: RwLock<Option<JsonObject>>,
     changed: Notify,
+    /// When the shard stopped being ready, if it is not ready.
+    not_ready_since: RwLock<Option<Instant>>,
 }
 
 impl Ready {
@@ -20,6 +44,7 @@ impl Ready {
         Self {
             inner: RwLock::new(None),
             changed: Notify::new(),
+            not_ready_since: RwLock::new(Some(Instant::now())),
         }
     }
 
@@ -33,14 +58,27 @@ impl Ready {
 
     pub fn set_ready(&self, payload: JsonObject) {
         *self.inner.write().unwrap() = Some(payload);
+        *self.not_ready_since.write().unwrap() = None;
         self.changed.notify_waiters();
     }
 
     pub fn set_not_ready(&self) {
         *self.inner.write().unwrap() = None;
+        self.not_ready_since
+            .write()
+            .unwrap()
+            .get_or_insert_with(Instant::now);
         self.changed.notify_waiters();
     }
 
+    /// Time for which the shard has not been ready, if it is not ready.
+    pub fn not_ready_for(&self) -> Option<Duration> {
+        self.not_ready_since
+            .read()
+            .unwrap()
+            .map(|since| since.elapsed())
+    }
+
     pub async fn wait_until_ready(&self) -> JsonObject {
         while !self.is_ready() {
             self.wait_changed().await;
@@ -50,18 +88,131 @@ impl Ready {
     }
 }
 
+/// Information required to resume a shard's Discord session elsewhere.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResumeInfo {
+    /// ID of the shard the session belongs to.
+    pub shard_id: u32,
+    /// Discord session ID.
+    pub session_id: String,
+    /// Last sequence number received from Discord.
+    pub sequence: u64,
+    /// URL to use for resuming the session.
+    pub resume_url: Option<String>,
+}
+
+/// Tracker for the Discord session of a shard.
+pub struct DiscordSession {
+    /// Session ID and resume URL, if a session is active.
+    inner: RwLock<Option<(String, Option<String>)>>,
+    sequence: AtomicU64,
+}
+
+impl DiscordSession {
+    pub const fn new() -> Self {
+        Self {
+            inner: RwLock::new(None),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, session_id: String, resume_url: Option<String>) {
+        *self.inner.write().unwrap() = Some((session_id, resume_url));
+    }
+
+    pub fn clear(&self) {
+        *self.inner.write().unwrap() = None;
+        self.sequence.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_sequence(&self, sequence: u64) {
+        self.sequence.store(sequence, Ordering::Relaxed);
+    }
+
+    pub fn resume_info(&self, shard_id: u32) -> Option<ResumeInfo> {
+        let (session_id, resume_url) = self.inner.read().unwrap().clone()?;
+
+        Some(ResumeInfo {
+            shard_id,
+            session_id,
+            sequence: self.sequence.load(Ordering::Relaxed),
+            resume_url,
+        })
+    }
+}
+
+/// Connection details of a shard, kept up to date by the task driving it.
+#[derive(Clone, Copy)]
+pub struct Status {
+    /// State of the connection to Discord.
+    pub connection: ConnectionState,
+    /// Most recent heartbeat latency.
+    pub latency: Option<Duration>,
+    /// Dispatch events received per second since the last metrics update.
+    pub events_per_second: f64,
+    /// When the last READY was received.
+    pub ready_at: Option<Instant>,
+    /// When the last RESUMED was received.
+    pub resumed_at: Option<Instant>,
+}
+
+impl Status {
+    pub const fn new() -> Self {
+        Self {
+            connection: ConnectionState::Identifying,
+            latency: None,
+            events_per_second: 0.0,
+            ready_at: None,
+            resumed_at: None,
+        }
+    }
+}
+
 /// State of a single shard.
 pub struct Shard {
     /// ID of this shard.
     pub id: u32,
-    /// Sender for this shard.
-    pub sender: MessageSender,
+    /// Config of the bot this shard belongs to as it was started with, the
+    /// token, intents and presence may have been reloaded since.
+    pub bot: Arc<Bot>,
+    /// Sender for this shard, replaced when the shard is reconfigured.
+    pub sender: RwLock<MessageSender>,
+    /// Handle for sending commands to the task driving this shard, replaced
+    /// when the task is restarted.
+    pub commands: RwLock<mpsc::UnboundedSender<ShardCommand>>,
+    /// Gateway configuration to use when the shard is started again.
+    pub config: RwLock<Config<IdentifyQueue>>,
+    /// Token that clients of this shard have to use.
+    pub token: RwLock<String>,
     /// Handle for broadcasting events for this shard.
     pub events: broadcast::Sender<BroadcastMessage>,
     /// READY state manager for this shard.
     pub ready: Ready,
     /// Cache for guilds on this shard.
     pub guilds: cache::Guilds,
+    /// Discord session of this shard.
+    pub session: DiscordSession,
+    /// Connection details of this shard.
+    pub status: RwLock<Status>,
+    /// Presence that was reloaded from the config and has to be set
+    /// again whenever the shard identifies.
+    pub presence: RwLock<Option<UpdatePresencePayload>>,
+}
+
+impl Shard {
+    /// Check whether a token sent by a client is valid for this shard.
+    pub fn validate_token(&self, token: &str) -> bool {
+        !CONFIG.validate_token || self.is_bot_token(token)
+    }
+
+    /// Check whether a token sent by a client is the bot token of this shard.
+    pub fn is_bot_token(&self, token: &str) -> bool {
+        // Discord tokens may be prefixed by 'Bot ' in IDENTIFY and RESUME
+        token
+            .split_whitespace()
+            .last()
+            .is_some_and(|token| secrets_match(token, &self.token.read().unwrap()))
+    }
 }
 
 /// A session initiated by a client.
@@ -73,17 +224,41 @@ pub struct Session {
     pub compress: Option<bool>,
 }
 
+/// A client connection that has a session.
+pub struct Connection {
+    /// Address of the client.
+    pub addr: SocketAddr,
+    /// Shard ID that the client receives events of.
+    pub shard_id: u32,
+    /// Notified when the connection should be closed.
+    pub close: Arc<Notify>,
+}
+
 /// Global state for all shards managed by the proxy.
 pub struct Inner {
     /// State of all shards managed by the proxy.
     pub shards: Vec<Arc<Shard>>,
     /// Total shard count.
     pub shard_count: u32,
+    /// Identify queue shared by all shards.
+    pub queue: IdentifyQueue,
     /// All sessions active in the proxy.
     pub sessions: RwLock<HashMap<String, Session>>,
+    /// Connected clients by their session ID.
+    pub connections: RwLock<HashMap<String, Connection>>,
+    /// Guild of every cached guild channel.
+    pub channel_guilds: Arc<cache::ChannelGuilds>,
 }
 
 impl Inner {
+    /// Get the shard that a guild is on, if the proxy runs it.
+    pub fn shard_for_guild(&self, guild_id: Id<GuildMarker>) -> Option<&Arc<Shard>> {
+        let shard_id = ((guild_id.get() >> 22) % u64::from(self.shard_count)) as u32;
+        let index = shard_id.checked_sub(self.shards.first()?.id)?;
+
+        self.shards.get(index as usize)
+    }
+
     /// Get a session by its ID.
     pub fn get_session(&self, session_id: &str) -> Option<Session> {
         self.sessions.read().unwrap().get(session_id).cloned()
@@ -106,7 +281,151 @@ impl Inner {
 
         session_id
     }
+
+    /// Keep track of a client connection for a session.
+    pub fn add_connection(&self, session_id: String, connection: Connection) {
+        self.connections
+            .write()
+            .unwrap()
+            .insert(session_id, connection);
+    }
+
+    /// Stop tracking the client connection of a session.
+    pub fn remove_connection(&self, session_id: &str) {
+        self.connections.write().unwrap().remove(session_id);
+    }
+
+    /// Ask the client connection of a session to close.
+    ///
+    /// Returns `false` if no client is connected with this session.
+    pub fn close_connection(&self, session_id: &str) -> bool {
+        let connections = self.connections.read().unwrap();
+
+        if let Some(connection) = connections.get(session_id) {
+            connection.close.notify_one();
+            true
+        } else {
+            false
+        }
+    }
 }
 
-/// A reference to the [`StateInner`] of the proxy.
+/// A reference to the [`Inner`] state of the proxy.
 pub type State = Arc<Inner>;
+
+/// State of a bot that outlives its shards when resharding.
+pub struct Proxy {
+    /// Config of the bot with the token, intents and presence that were
+    /// last reloaded.
+    bot: RwLock<Arc<Bot>>,
+    /// State of the shards that are currently in use.
+    current: RwLock<State>,
+    /// Tasks driving all shards.
+    pub dispatch_tasks: Mutex<JoinSet<()>>,
+    /// HTTP client for the Discord API, using the current token.
+    client: RwLock<Arc<Client>>,
+    /// Whether resharding is in progress.
+    pub resharding: AtomicBool,
+}
+
+impl Proxy {
+    pub const fn new(
+        bot: Arc<Bot>,
+        state: State,
+        dispatch_tasks: JoinSet<()>,
+        client: Arc<Client>,
+    ) -> Self {
+        Self {
+            bot: RwLock::new(bot),
+            current: RwLock::new(state),
+            dispatch_tasks: Mutex::new(dispatch_tasks),
+            client: RwLock::new(client),
+            resharding: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the current config of the bot.
+    pub fn bot(&self) -> Arc<Bot> {
+        self.bot.read().unwrap().clone()
+    }
+
+    /// Get the HTTP client for the current token.
+    pub fn client(&self) -> Arc<Client> {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Apply the activity and status of a reloaded config of the bot.
+    pub fn reload_presence(&self, reloaded: &Bot) {
+        let mut current = self.bot.write().unwrap();
+        let bot = Arc::make_mut(&mut current);
+
+        bot.activity.clone_from(&reloaded.activity);
+        bot.status = reloaded.status;
+    }
+
+    /// Apply the token and intents of a reloaded config of the bot, together
+    /// with a client for the token.
+    pub fn reload_credentials(&self, reloaded: &Bot, client: Arc<Client>) {
+        // Both are replaced at once, so the token of the bot always matches
+        // the one of the client
+        let mut current = self.bot.write().unwrap();
+        let mut current_client = self.client.write().unwrap();
+
+        let bot = Arc::make_mut(&mut current);
+        bot.token.clone_from(&reloaded.token);
+        bot.intents = reloaded.intents;
+        *current_client = client;
+    }
+
+    /// Get the state of the shards that are currently in use.
+    pub fn state(&self) -> State {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the state of the shards that are in use, returning the
+    /// previous one.
+    pub fn replace(&self, state: State) -> State {
+        std::mem::replace(&mut *self.current.write().unwrap(), state)
+    }
+}
+
+/// All bots run by the proxy.
+pub struct Bots {
+    /// The default bot first, then the others in the order of the config.
+    pub bots: Vec<Arc<Proxy>>,
+    /// TLS certificates of the server, if TLS is enabled.
+    pub tls: Option<tls::Certificates>,
+}
+
+impl Bots {
+    /// The bot configured by the top-level fields, which clients connect to
+    /// unless they pick another one.
+    pub fn default_bot(&self) -> &Arc<Proxy> {
+        &self.bots[0]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Proxy>> {
+        self.bots.iter().find(|proxy| proxy.bot().name == name)
+    }
+
+    /// Find the bot that a token sent by a client belongs to.
+    pub fn by_token(&self, token: &str) -> Option<&Arc<Proxy>> {
+        self.bots.iter().find(|proxy| {
+            proxy
+                .state()
+                .shards
+                .first()
+                .is_some_and(|shard| shard.is_bot_token(token))
+        })
+    }
+
+    /// Find a session and the state of the bot it belongs to.
+    pub fn session(&self, session_id: &str) -> Option<(State, Session)> {
+        self.bots.iter().find_map(|proxy| {
+            let state = proxy.state();
+            let session = state.get_session(session_id)?;
+
+            Some((state, session))
+        })
+    }
+}
@@ -0,0 +1,247 @@
+use redis::{aio::ConnectionManager, Client, RedisError};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+
+use std::{sync::OnceLock, time::Duration};
+
+use crate::config::Redis;
+
+/// Maximum number of entries that are sent to Redis in one pipeline.
+const BATCH_SIZE: usize = 256;
+
+/// Time to wait before connecting or sending a batch again after it failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+static SINK: OnceLock<(mpsc::Sender<Entry>, &'static Redis)> = OnceLock::new();
+
+/// A dispatched event waiting to be appended to a stream.
+struct Entry {
+    stream: String,
+    bot: String,
+    shard: String,
+    event: String,
+    payload: String,
+}
+
+/// Start publishing events to the Redis Streams configured in `redis`.
+pub fn start(config: &'static Redis) -> Result<(), RedisError> {
+    let client = Client::open(config.url.as_str())?;
+    let (tx, rx) = mpsc::channel(config.buffer);
+
+    if SINK.set((tx, config)).is_ok() {
+        tokio::spawn(run(client, config, rx));
+    }
+
+    Ok(())
+}
+
+/// Queue a dispatched event for publishing, if publishing is enabled and the
+/// event is not filtered out. Events are dropped instead of blocking the
+/// shard when the buffer is full.
+pub fn publish(bot: &str, shard: &str, event: &str, payload: &str) {
+    let Some((sink, config)) = SINK.get() else {
+        return;
+    };
+
+    if config
+        .events
+        .as_ref()
+        .is_some_and(|events| !events.contains(event))
+    {
+        return;
+    }
+
+    let entry = Entry {
+        stream: config
+            .stream
+            .replace("{bot}", bot)
+            .replace("{shard}", shard)
+            .replace("{event}", event),
+        bot: bot.to_owned(),
+        shard: shard.to_owned(),
+        event: event.to_owned(),
+        payload: payload.to_owned(),
+    };
+
+    match sink.try_send(entry) {
+        Ok(()) => {}
+        Err(TrySendError::Full(entry)) => {
+            metrics::counter!("gateway_redis_dropped", "bot" => entry.bot, "reason" => "buffer_full")
+                .increment(1);
+        }
+        Err(TrySendError::Closed(entry)) => {
+            metrics::counter!("gateway_redis_dropped", "bot" => entry.bot, "reason" => "closed")
+                .increment(1);
+        }
+    }
+}
+
+/// Connect to Redis, retrying until it is available.
+async fn connect(client: &Client) -> ConnectionManager {
+    loop {
+        match ConnectionManager::new(client.clone()).await {
+            Ok(connection) => {
+                info!("Connected to Redis for publishing events");
+                metrics::gauge!("gateway_redis_connected").set(1.0);
+                return connection;
+            }
+            Err(e) => {
+                warn!("Failed to connect to Redis: {e}");
+                metrics::gauge!("gateway_redis_connected").set(0.0);
+                sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Whether sending again may succeed once the connection is restored.
+fn is_transient(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+async fn run(client: Client, config: &'static Redis, mut rx: mpsc::Receiver<Entry>) {
+    let mut connection = connect(&client).await;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    while rx.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+        let mut pipeline = redis::pipe();
+
+        for entry in &batch {
+            pipeline.cmd("XADD").arg(&entry.stream);
+
+            if let Some(max_len) = config.max_len {
+                pipeline.arg("MAXLEN").arg("~").arg(max_len);
+            }
+
+            pipeline
+                .arg("*")
+                .arg("bot")
+                .arg(&entry.bot)
+                .arg("shard")
+                .arg(&entry.shard)
+                .arg("event")
+                .arg(&entry.event)
+                .arg("payload")
+                .arg(&entry.payload)
+                .ignore();
+        }
+
+        // The connection manager reconnects in the background, so a batch is
+        // sent again until Redis is reachable. Meanwhile, new events pile up
+        // in the buffer.
+        let published = loop {
+            let result: Result<(), RedisError> = pipeline.query_async(&mut connection).await;
+
+            match result {
+                Ok(()) => {
+                    metrics::gauge!("gateway_redis_connected").set(1.0);
+                    break true;
+                }
+                Err(e) if is_transient(&e) => {
+                    warn!("Failed to publish {} events to Redis: {e}", batch.len());
+                    metrics::gauge!("gateway_redis_connected").set(0.0);
+                    sleep(RETRY_DELAY).await;
+                }
+                Err(e) => {
+                    error!("Redis rejected {} events: {e}", batch.len());
+                    break false;
+                }
+            }
+        };
+
+        for entry in batch.drain(..) {
+            if published {
+                metrics::counter!("gateway_redis_published", "bot" => entry.bot).increment(1);
+            } else {
+                metrics::counter!("gateway_redis_dropped", "bot" => entry.bot, "reason" => "rejected")
+                    .increment(1);
+            }
+        }
+
+        metrics::gauge!("gateway_redis_buffered")
+            .set(config.buffer.saturating_sub(rx.capacity()) as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::{aio::ConnectionManager, Client};
+    use tokio::time::{sleep, timeout};
+
+    use std::{
+        collections::HashMap,
+        env, process,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use super::{publish, start};
+    use crate::config::Redis;
+
+    /// Entries returned by `XRANGE`, as pairs of ID and fields.
+    type Range = Vec<(String, HashMap<String, String>)>;
+
+    #[tokio::test]
+    #[ignore = "requires a Redis server at REDIS_URL"]
+    async fn publishes_dispatches_to_streams() {
+        let url = env::var("REDIS_URL").expect("REDIS_URL should be set");
+        let nonce = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let prefix = format!("gateway-test:{}:{nonce}", process::id());
+
+        let config: &'static Redis = Box::leak(Box::new(Redis {
+            url: url.clone(),
+            stream: format!("{prefix}:{{bot}}:{{shard}}"),
+            events: None,
+            max_len: None,
+            buffer: 16,
+        }));
+        start(config).unwrap();
+
+        let payload = r#"{"op":0,"t":"MESSAGE_CREATE","s":1,"d":{}}"#;
+        publish("default", "0", "MESSAGE_CREATE", payload);
+
+        let mut connection = ConnectionManager::new(Client::open(url).unwrap())
+            .await
+            .unwrap();
+        let stream = format!("{prefix}:default:0");
+
+        let range = timeout(Duration::from_secs(10), async {
+            loop {
+                let range: Range = redis::cmd("XRANGE")
+                    .arg(&stream)
+                    .arg("-")
+                    .arg("+")
+                    .query_async(&mut connection)
+                    .await
+                    .unwrap();
+
+                if !range.is_empty() {
+                    break range;
+                }
+
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("event should be published within 10 seconds");
+
+        let deleted: usize = redis::cmd("DEL")
+            .arg(&stream)
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 1);
+        assert_eq!(range.len(), 1);
+        let fields = &range[0].1;
+        assert_eq!(fields["bot"], "default");
+        assert_eq!(fields["shard"], "0");
+        assert_eq!(fields["event"], "MESSAGE_CREATE");
+        assert_eq!(fields["payload"], payload);
+    }
+}
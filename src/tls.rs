@@ -0,0 +1,164 @@
+use rustls::{
+    crypto::aws_lc_rs::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{VerifierBuilderError, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
+
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use crate::config::Tls;
+
+/// Symlink in the directory of a mounted Kubernetes secret that is replaced
+/// when the secret changes.
+const KUBERNETES_DATA_DIR: &str = "..data";
+
+pub enum Error {
+    Io(String, io::Error),
+    NoCertificates(String),
+    NoPrivateKey(String),
+    Rustls(rustls::Error),
+    ClientVerifier(VerifierBuilderError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Io(path, e) => f.write_fmt(format_args!("Failed to read {path}: {e}")),
+            Self::NoCertificates(path) => {
+                f.write_fmt(format_args!("No certificates found in {path}"))
+            }
+            Self::NoPrivateKey(path) => f.write_fmt(format_args!("No private key found in {path}")),
+            Self::Rustls(e) => e.fmt(f),
+            Self::ClientVerifier(e) => e.fmt(f),
+        }
+    }
+}
+
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let file = File::open(path).map_err(|e| Error::Io(path.to_string(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::Io(path.to_string(), e))?;
+
+    if certificates.is_empty() {
+        return Err(Error::NoCertificates(path.to_string()));
+    }
+
+    Ok(certificates)
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let file = File::open(path).map_err(|e| Error::Io(path.to_string(), e))?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| Error::Io(path.to_string(), e))?
+        .ok_or_else(|| Error::NoPrivateKey(path.to_string()))
+}
+
+/// Build the rustls config from the files configured in `tls`.
+fn server_config(tls: &Tls) -> Result<ServerConfig, Error> {
+    let builder = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(Error::Rustls)?;
+
+    // With a client CA, only clients with a certificate signed by it can connect
+    let builder = if let Some(client_ca) = &tls.client_ca {
+        let mut roots = RootCertStore::empty();
+
+        for certificate in read_certificates(client_ca)? {
+            roots.add(certificate).map_err(Error::Rustls)?;
+        }
+
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+            .build()
+            .map_err(Error::ClientVerifier)?;
+
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let mut config = builder
+        .with_single_cert(read_certificates(&tls.cert)?, read_private_key(&tls.key)?)
+        .map_err(Error::Rustls)?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// TLS config of the server that can be swapped out when the certificates
+/// change. Connections that are already established keep the config they
+/// were accepted with.
+pub struct Certificates {
+    tls: Tls,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl Certificates {
+    pub fn load(tls: &Tls) -> Result<Self, Error> {
+        Ok(Self {
+            tls: tls.clone(),
+            config: RwLock::new(Arc::new(server_config(tls)?)),
+        })
+    }
+
+    /// Paths of all files the certificates are loaded from.
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        [
+            Some(&self.tls.cert),
+            Some(&self.tls.key),
+            self.tls.client_ca.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(Path::new)
+    }
+
+    /// Directories of all files the certificates are loaded from.
+    ///
+    /// Tools like certbot and Kubernetes replace certificates by renaming
+    /// files or symlinks, which can only be noticed by watching the
+    /// directories.
+    pub fn directories(&self) -> HashSet<&Path> {
+        self.paths()
+            .map(|path| match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            })
+            .collect()
+    }
+
+    /// Whether a change to the entry `name` in one of the
+    /// [`directories`](Self::directories) may have replaced a certificate.
+    pub fn is_affected_by(&self, name: &OsStr) -> bool {
+        // Kubernetes swaps the ..data symlink that the files point into
+        name == KUBERNETES_DATA_DIR || self.paths().any(|path| path.file_name() == Some(name))
+    }
+
+    /// Load the certificates again, keeping the current ones if that fails.
+    pub fn reload(&self) {
+        match server_config(&self.tls) {
+            Ok(config) => {
+                *self.config.write().unwrap() = Arc::new(config);
+                info!("Reloaded TLS certificates");
+            }
+            Err(e) => error!("TLS certificates were modified, but failed to reload: {e}"),
+        }
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap().clone())
+    }
+}
@@ -13,9 +13,12 @@ use hyper_util::rt::TokioIo;
 use ring::digest;
 use tracing::error;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
-use crate::{server::handle_client, state::State};
+use crate::{
+    server::handle_client,
+    state::{Bots, Proxy},
+};
 
 /// Websocket GUID constant as specified in RFC6455:
 /// <https://datatracker.ietf.org/doc/html/rfc6455#section-1.3>
@@ -29,7 +32,8 @@ const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 pub fn server(
     addr: SocketAddr,
     mut request: Request<Incoming>,
-    state: State,
+    bots: Arc<Bots>,
+    bot: Option<Arc<Proxy>>,
 ) -> Response<Full<Bytes>> {
     let uri = request.uri();
     let query = uri.query();
@@ -56,7 +60,8 @@ pub fn server(
         tokio::spawn(async move {
             match upgrade::on(&mut request).await {
                 Ok(upgraded) => {
-                    let _res = handle_client(addr, TokioIo::new(upgraded), state, use_zlib).await;
+                    let _res =
+                        handle_client(addr, TokioIo::new(upgraded), bots, bot, use_zlib).await;
                 }
                 Err(e) => error!("[{}] Websocket upgrade error: {}", addr, e),
             }
@@ -0,0 +1,574 @@
+use hyper::Uri;
+use tracing_subscriber::filter::LevelFilter;
+use twilight_gateway::Intents;
+
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+};
+
+use crate::config::{Config, Role, DEFAULT_BOT};
+
+const MIN_CLIENT_KEY_LENGTH: usize = 32;
+
+/// A problem with the value of a config field.
+pub struct Problem {
+    /// Path of the field, like `cache.members`.
+    pub field: String,
+    pub message: String,
+    /// Whether the config still works, just not as intended.
+    pub warning: bool,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_fmt(format_args!("{}: {}", self.field, self.message))
+    }
+}
+
+/// Whether a URL has one of the schemes and a host.
+fn is_url(url: &str, schemes: &[&str]) -> bool {
+    url.parse::<Uri>().is_ok_and(|uri| {
+        uri.scheme_str()
+            .is_some_and(|scheme| schemes.contains(&scheme))
+            && uri.host().is_some()
+    })
+}
+
+/// Check for values and combinations of values that are valid to deserialize
+/// but would fail or be ignored later on.
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |field: String, message: String| {
+        problems.push(Problem {
+            field,
+            message,
+            warning: false,
+        });
+    };
+    let mut warnings = Vec::new();
+
+    if LevelFilter::from_str(&config.log_level).is_err() {
+        problem(
+            String::from("log_level"),
+            format!(
+                "unknown level {:?}, use one of off, error, warn, info, debug or trace",
+                config.log_level
+            ),
+        );
+    }
+
+    let mut bot_names = HashSet::new();
+    let mut bot_tokens = HashSet::new();
+
+    for (index, bot) in config.bots().into_iter().enumerate() {
+        // Fields of the default bot are at the top level
+        let is_default = index == 0;
+        let prefix = if is_default {
+            String::new()
+        } else {
+            // config.bots does not contain the default bot
+            format!("bots[{}].", index - 1)
+        };
+        let field = |name: &str| format!("{prefix}{name}");
+
+        if !is_default {
+            if bot.name.is_empty()
+                || !bot
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                problem(
+                    field("name"),
+                    format!(
+                        "{:?} is not a valid bot name, use letters, digits, - and _",
+                        bot.name
+                    ),
+                );
+            }
+
+            if bot.name == DEFAULT_BOT {
+                problem(
+                    field("name"),
+                    format!(
+                        "{DEFAULT_BOT:?} is the name of the bot configured by the top-level fields"
+                    ),
+                );
+            } else if !bot_names.insert(bot.name.clone()) {
+                problem(
+                    field("name"),
+                    format!("{:?} is used by more than one bot", bot.name),
+                );
+            }
+        }
+
+        // Clients that do not connect to the path of a bot are routed by
+        // their token
+        if !bot_tokens.insert(bot.token.clone()) {
+            problem(
+                field("token"),
+                String::from("is already used by another bot"),
+            );
+        }
+
+        if bot.shards == Some(0) {
+            problem(
+                field("shards"),
+                String::from(
+                    "must be at least 1, omit it to use the shard count recommended by Discord",
+                ),
+            );
+        }
+
+        let shard_start = bot.shard_start.unwrap_or(0);
+
+        match (bot.shards, bot.shard_end) {
+            (_, Some(shard_end)) if shard_start >= shard_end => problem(
+                field("shard_start"),
+                format!("must be lower than shard_end ({shard_end}), shard_end is exclusive"),
+            ),
+            (Some(shards), Some(shard_end)) if shard_end > shards => problem(
+                field("shard_end"),
+                format!("must not be higher than shards ({shards}), shard_end is exclusive"),
+            ),
+            (Some(shards), None) if shard_start >= shards => problem(
+                field("shard_start"),
+                format!("must be lower than shards ({shards}), shard IDs start at 0"),
+            ),
+            _ => {}
+        }
+
+        let cache = &bot.cache;
+        let required_intents = [
+            (cache.channels, "cache.channels", Intents::GUILDS, "GUILDS"),
+            (cache.roles, "cache.roles", Intents::GUILDS, "GUILDS"),
+            (
+                cache.stage_instances,
+                "cache.stage_instances",
+                Intents::GUILDS,
+                "GUILDS",
+            ),
+            (
+                cache.members,
+                "cache.members",
+                Intents::GUILD_MEMBERS,
+                "GUILD_MEMBERS",
+            ),
+            (
+                cache.presences,
+                "cache.presences",
+                Intents::GUILD_PRESENCES,
+                "GUILD_PRESENCES",
+            ),
+            (
+                cache.emojis,
+                "cache.emojis",
+                Intents::GUILD_EMOJIS_AND_STICKERS,
+                "GUILD_EMOJIS_AND_STICKERS",
+            ),
+            (
+                cache.stickers,
+                "cache.stickers",
+                Intents::GUILD_EMOJIS_AND_STICKERS,
+                "GUILD_EMOJIS_AND_STICKERS",
+            ),
+            (
+                cache.scheduled_events,
+                "cache.scheduled_events",
+                Intents::GUILD_SCHEDULED_EVENTS,
+                "GUILD_SCHEDULED_EVENTS",
+            ),
+            (
+                cache.voice_states,
+                "cache.voice_states",
+                Intents::GUILD_VOICE_STATES,
+                "GUILD_VOICE_STATES",
+            ),
+            (
+                cache.messages > 0,
+                "cache.messages",
+                Intents::GUILD_MESSAGES,
+                "GUILD_MESSAGES",
+            ),
+        ];
+
+        for (enabled, name, intent, intent_name) in required_intents {
+            if enabled && !bot.intents.contains(intent) {
+                problem(
+                    field(name),
+                    format!(
+                        "requires the {intent_name} intent, add {} to {} or disable it",
+                        intent.bits(),
+                        field("intents")
+                    ),
+                );
+            }
+        }
+
+        // Messages are received without content, so the history would only
+        // have empty messages
+        if cache.messages > 0 && !bot.intents.contains(Intents::MESSAGE_CONTENT) {
+            warnings.push(Problem {
+                field: field("cache.messages"),
+                message: format!(
+                    "cached messages have no content without the MESSAGE_CONTENT intent, add {} to {}",
+                    Intents::MESSAGE_CONTENT.bits(),
+                    field("intents")
+                ),
+                warning: true,
+            });
+        }
+
+        if let Some(url) = &bot.queue.url {
+            if !is_url(url, &["http"]) {
+                problem(
+                    field("queue.url"),
+                    format!(
+                        "{url:?} is not an HTTP URL, it should look like \"http://queue:{}/queue\"",
+                        config.port
+                    ),
+                );
+            }
+
+            if bot.queue.serve {
+                problem(
+                    field("queue.serve"),
+                    String::from(
+                        "only the process that owns the queue can serve it, remove url or serve",
+                    ),
+                );
+            }
+        }
+
+        if bot.queue.serve && config.admin_token.is_none() {
+            problem(
+                field("queue.serve"),
+                String::from("requires admin_token, which other processes authenticate with"),
+            );
+        }
+    }
+
+    if config.standby.is_some() && !config.bots.is_empty() {
+        problem(
+            String::from("standby"),
+            String::from("is not supported together with bots"),
+        );
+    }
+
+    // The standby and processes using a shared queue connect over plain HTTP
+    let has_peers = config.standby.is_some() || config.bots().iter().any(|bot| bot.queue.serve);
+    let has_plain_admin_listener = config.listeners().iter().any(|listener| {
+        listener.address.is_some() && !listener.tls && listener.roles.contains(&Role::Admin)
+    });
+
+    if config.tls.is_some() && has_peers && !has_plain_admin_listener {
+        problem(
+            String::from("listeners"),
+            String::from(
+                "standby instances and processes sharing the identify queue connect over plain HTTP, add a TCP listener with the admin role and \"tls\": false",
+            ),
+        );
+    }
+
+    if config.tls.is_some() && !config.externally_accessible_url.starts_with("wss://") {
+        problem(
+            String::from("externally_accessible_url"),
+            String::from("must use wss:// when tls is enabled"),
+        );
+    } else if !is_url(&config.externally_accessible_url, &["ws", "wss"]) {
+        problem(
+            String::from("externally_accessible_url"),
+            format!(
+                "{:?} is not a WebSocket URL, it should look like \"ws://localhost:{}\"",
+                config.externally_accessible_url, config.port
+            ),
+        );
+    }
+
+    match config.webhook_url.as_deref() {
+        Some("") => problem(
+            String::from("webhook_url"),
+            String::from("is empty, omit it to disable logging to a webhook"),
+        ),
+        Some(webhook_url) if !is_url(webhook_url, &["https"]) => problem(
+            String::from("webhook_url"),
+            String::from("is not an HTTPS URL, copy the URL of the webhook from Discord"),
+        ),
+        _ => {}
+    }
+
+    if config.ready_threshold > 100 {
+        problem(
+            String::from("ready_threshold"),
+            format!(
+                "is a percentage and must not be higher than 100, got {}",
+                config.ready_threshold
+            ),
+        );
+    }
+
+    for (index, listener) in config.listeners.iter().enumerate() {
+        let path = format!("listeners[{index}]");
+        let field = |name: &str| format!("{path}.{name}");
+
+        match (listener.address, &listener.path) {
+            (Some(_), Some(_)) | (None, None) => problem(
+                path.clone(),
+                String::from("every listener needs either an address or a path, but not both"),
+            ),
+            (Some(address), None) if listener.mode.is_some() => problem(
+                field("mode"),
+                format!(
+                    "only applies to Unix domain sockets, remove it from the listener on {address}"
+                ),
+            ),
+            (None, Some(path)) if listener.allow.is_some() => problem(
+                field("allow"),
+                format!("only applies to TCP listeners, remove it from the listener on {path}"),
+            ),
+            (None, Some(path)) if !listener.trusted_proxies.is_empty() => problem(
+                field("trusted_proxies"),
+                format!("only applies to TCP listeners, remove it from the listener on {path}"),
+            ),
+            _ => {}
+        }
+
+        if listener.mode.is_some() && listener.mode().is_none() {
+            problem(
+                field("mode"),
+                format!(
+                    "{:?} is not an octal file mode, it should look like \"660\"",
+                    listener.mode.as_deref().unwrap_or_default()
+                ),
+            );
+        }
+
+        if listener.roles.is_empty() {
+            problem(
+                field("roles"),
+                String::from("must contain at least one of gateway, cache, metrics or admin"),
+            );
+        }
+    }
+
+    let mut client_names = HashSet::new();
+
+    for (index, client) in config.clients.iter().enumerate() {
+        let field = |name: &str| format!("clients[{index}].{name}");
+
+        if client.name.is_empty() || client.name.contains('.') {
+            problem(
+                field("name"),
+                format!(
+                    "{:?} is not a valid client name, it must not be empty or contain dots",
+                    client.name
+                ),
+            );
+        } else if !client_names.insert(&client.name) {
+            problem(
+                field("name"),
+                format!("{:?} is used by more than one client", client.name),
+            );
+        }
+
+        if client.key.len() < MIN_CLIENT_KEY_LENGTH {
+            problem(
+                field("key"),
+                format!(
+                    "the key of client {:?} must be at least {MIN_CLIENT_KEY_LENGTH} characters long",
+                    client.name
+                ),
+            );
+        }
+    }
+
+    if let Some(redis) = &config.redis {
+        if cfg!(not(feature = "redis")) {
+            problem(
+                String::from("redis"),
+                String::from("requires a build with the redis feature enabled"),
+            );
+        }
+
+        if !is_url(&redis.url, &["redis", "rediss"]) {
+            problem(
+                String::from("redis.url"),
+                format!(
+                    "{:?} is not a Redis URL, it should look like \"redis://localhost:6379\"",
+                    redis.url
+                ),
+            );
+        }
+
+        if redis.stream.is_empty() {
+            problem(
+                String::from("redis.stream"),
+                String::from("must not be empty, omit it to use one stream per shard"),
+            );
+        }
+
+        if redis.max_len == Some(0) {
+            problem(
+                String::from("redis.max_len"),
+                String::from("must be at least 1, omit it to keep all entries"),
+            );
+        }
+
+        if redis.buffer == 0 {
+            problem(
+                String::from("redis.buffer"),
+                String::from("must be at least 1"),
+            );
+        }
+    }
+
+    if let Some(standby) = &config.standby {
+        if config.admin_token.is_none() {
+            problem(
+                String::from("standby"),
+                String::from("requires admin_token, which the standby fetches the sessions with"),
+            );
+        }
+
+        if !is_url(&standby.primary_url, &["http"]) {
+            problem(
+                String::from("standby.primary_url"),
+                format!(
+                    "{:?} is not an HTTP URL, it should look like \"http://primary:{}\"",
+                    standby.primary_url, config.port
+                ),
+            );
+        }
+
+        if standby.sync_interval == 0 {
+            problem(
+                String::from("standby.sync_interval"),
+                String::from("must be at least 1 millisecond"),
+            );
+        }
+
+        if standby.failover_timeout == 0 {
+            problem(
+                String::from("standby.failover_timeout"),
+                String::from("must be at least 1 millisecond"),
+            );
+        }
+    }
+
+    problems.extend(warnings);
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_gateway::Intents;
+
+    use crate::config::{ClientKey, Config, HttpAccess};
+
+    use super::validate;
+
+    fn fields(config: &Config) -> Vec<String> {
+        validate(config)
+            .into_iter()
+            .map(|problem| problem.field)
+            .collect()
+    }
+
+    #[test]
+    fn accepts_the_template() {
+        assert!(fields(&Config::template()).is_empty());
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let mut config = Config::template();
+        config.ready_threshold = 101;
+        config.log_level = String::from("verbose");
+
+        assert_eq!(fields(&config), ["log_level", "ready_threshold"]);
+    }
+
+    #[test]
+    fn indexes_bot_fields() {
+        let mut config = Config::template();
+        config.token = String::from("default-token");
+
+        let mut bot = config.bots().remove(0);
+        bot.name = String::from("music");
+        bot.token = String::from("music-token");
+        config.bots.push(bot.clone());
+        bot.token = String::from("other-token");
+        config.bots.push(bot.clone());
+        bot.name = String::from("not valid");
+        bot.token = String::from("default-token");
+        config.bots.push(bot);
+
+        assert_eq!(
+            fields(&config),
+            ["bots[1].name", "bots[2].name", "bots[2].token"]
+        );
+    }
+
+    #[test]
+    fn indexes_listener_fields() {
+        let mut config = Config::template();
+
+        let mut both = config.listeners().remove(0);
+        both.path = Some(String::from("/run/gateway.sock"));
+        let mut tcp_with_mode = config.listeners().remove(0);
+        tcp_with_mode.mode = Some(String::from("660"));
+        config.listeners = vec![both, tcp_with_mode];
+
+        assert_eq!(fields(&config), ["listeners[0]", "listeners[1].mode"]);
+    }
+
+    #[test]
+    fn indexes_client_fields() {
+        let mut config = Config::template();
+        let client = ClientKey {
+            name: String::from("worker"),
+            key: String::from("a-sufficiently-long-key-for-worker"),
+            shards: None,
+            opcodes: None,
+            bots: None,
+            http: HttpAccess::Read,
+        };
+        config.clients = vec![
+            ClientKey {
+                key: String::from("short"),
+                ..client.clone()
+            },
+            client,
+        ];
+
+        assert_eq!(fields(&config), ["clients[0].key", "clients[1].name"]);
+    }
+
+    #[test]
+    fn requires_admin_token_to_serve_the_queue() {
+        let mut config = Config::template();
+        config.queue.serve = true;
+
+        assert_eq!(fields(&config), ["queue.serve"]);
+
+        config.admin_token = Some(String::from("admin"));
+        assert!(fields(&config).is_empty());
+    }
+
+    #[test]
+    fn warns_about_message_cache_without_content() {
+        let mut config = Config::template();
+        config.cache.messages = 100;
+        config.intents |= Intents::GUILD_MESSAGES;
+
+        let problems = validate(&config);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].field, "cache.messages");
+        assert!(problems[0].warning);
+
+        config.intents |= Intents::MESSAGE_CONTENT;
+        assert!(fields(&config).is_empty());
+    }
+}